@@ -0,0 +1,347 @@
+#![allow(dead_code)]
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ptr;
+use core::slice;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::boot;
+use crate::kprintln;
+use crate::pmm::{FRAME_SIZE, PMM};
+use crate::vmm;
+
+const RSDP_V1_SIZE: usize = 20;
+const SDT_HEADER_SIZE: usize = 36;
+
+// offsets into the RSDP
+const RSDP_REVISION: usize = 15;
+const RSDP_RSDT_ADDRESS: usize = 16;
+const RSDP_LENGTH: usize = 20;
+const RSDP_XSDT_ADDRESS: usize = 24;
+
+// offset of the length in every system description table header
+const SDT_LENGTH: usize = 4;
+
+#[derive(Debug)]
+pub enum AcpiError {
+    NoRsdp,
+    BadRsdpSignature,
+    BadRsdpChecksum,
+    BadRsdpLength(u32),
+    BadChecksum([u8; 4]),
+    TableTooShort([u8; 4]),
+    Unmappable(u64),
+}
+
+/// ACPI's Generic Address Structure, describing a register in some address space.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct GenericAddress {
+    pub address_space: u8, // 0 system memory, 1 system I/O
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+#[derive(Debug)]
+pub struct Processor {
+    pub acpi_id: u32,
+    pub apic_id: u32,
+    pub enabled: bool, // usable right away, as opposed to online capable
+}
+
+#[derive(Debug)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: u64,
+    pub gsi_base: u32,
+}
+
+#[derive(Debug)]
+pub struct InterruptOverride {
+    pub bus: u8, // always 0, ISA
+    pub irq: u8,
+    pub gsi: u32,
+    pub flags: u16, // MPS INTI flags: polarity in bits 0-1, trigger mode in bits 2-3
+}
+
+/// The Multiple APIC Description Table, signature "APIC".
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    pub has_8259: bool,
+    pub processors: Vec<Processor>,
+    pub ioapics: Vec<IoApicEntry>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+/// The High Precision Event Timer table.
+#[derive(Debug)]
+pub struct Hpet {
+    pub address: GenericAddress,
+    pub hpet_number: u8,
+    pub minimum_tick: u16,
+    pub comparators: u8,
+    pub counter_64bit: bool,
+    pub vendor_id: u16,
+}
+
+/// The Fixed ACPI Description Table, signature "FACP". Fields the firmware did not provide
+/// (older revisions are shorter) are 0.
+#[derive(Debug)]
+pub struct Fadt {
+    pub dsdt: u64, // physical address, only valid until `boot::reclaim` frees the tables
+
+    pub sci_interrupt: u16,
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm_timer_block: u32,
+    pub boot_architecture_flags: u16,
+    pub flags: u32,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+pub struct AcpiTables {
+    pub revision: u8,
+    pub madt: Option<Madt>,
+    pub hpet: Option<Hpet>,
+    pub fadt: Option<Fadt>,
+}
+
+static TABLES: AtomicPtr<AcpiTables> = AtomicPtr::new(ptr::null_mut());
+
+/// Reads a `T` at `offset` of `bytes`, None if it does not fit.
+fn read<T: Copy>(bytes: &[u8], offset: usize) -> Option<T> {
+    if offset + core::mem::size_of::<T>() > bytes.len() {
+        return None;
+    }
+    Some(unsafe { ptr::read_unaligned(bytes.as_ptr().add(offset) as *const T) })
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// Makes `len` bytes of physical memory at `phys` reachable through the HHDM. Limine only has
+/// to map usable memory there, the tables may live in reserved or ACPI NVS memory.
+fn map_physical<'a>(phys: u64, len: usize) -> Result<&'a [u8], AcpiError> {
+    let mut pmm = PMM.lock();
+    let mut virt = 0;
+
+    let first_page = phys & !(FRAME_SIZE as u64 - 1);
+    for page in (first_page..phys + len as u64).step_by(FRAME_SIZE) {
+        let page_virt = vmm::map_mmio(page, &mut pmm).map_err(|_| AcpiError::Unmappable(page))?;
+        if page == first_page {
+            virt = page_virt + (phys - first_page);
+        }
+    }
+
+    Ok(unsafe { slice::from_raw_parts(virt as *const u8, len) })
+}
+
+/// Maps the whole system description table at `phys` and checks its checksum.
+fn map_table<'a>(phys: u64) -> Result<&'a [u8], AcpiError> {
+    let header = map_physical(phys, SDT_HEADER_SIZE)?;
+    let signature: [u8; 4] = read(header, 0).unwrap();
+    let length: u32 = read(header, SDT_LENGTH).unwrap();
+
+    if (length as usize) < SDT_HEADER_SIZE {
+        return Err(AcpiError::TableTooShort(signature));
+    }
+
+    let table = map_physical(phys, length as usize)?;
+    if !checksum_ok(table) {
+        return Err(AcpiError::BadChecksum(signature));
+    }
+    Ok(table)
+}
+
+// Walks the RSDT (32 bit entries) or XSDT (64 bit entries) and returns every table it lists.
+fn root_entries(root: &[u8], entry_size: usize) -> impl Iterator<Item = u64> + '_ {
+    root[SDT_HEADER_SIZE..]
+        .chunks_exact(entry_size)
+        .map(move |entry| match entry_size {
+            4 => read::<u32>(entry, 0).unwrap() as u64,
+            _ => read::<u64>(entry, 0).unwrap(),
+        })
+}
+
+fn parse_madt(table: &[u8]) -> Madt {
+    const PCAT_COMPAT: u32 = 1 << 0;
+
+    let mut madt = Madt {
+        local_apic_address: read::<u32>(table, 36).unwrap_or(0) as u64,
+        has_8259: read::<u32>(table, 40).unwrap_or(0) & PCAT_COMPAT != 0,
+        processors: Vec::new(),
+        ioapics: Vec::new(),
+        overrides: Vec::new(),
+    };
+
+    let mut offset = 44;
+    while let (Some(entry_type), Some(length)) =
+        (read::<u8>(table, offset), read::<u8>(table, offset + 1))
+    {
+        let length = length as usize;
+        if length < 2 || offset + length > table.len() {
+            break;
+        }
+        let entry = &table[offset..offset + length];
+
+        match entry_type {
+            // processor local APIC
+            0 => madt.processors.push(Processor {
+                acpi_id: read::<u8>(entry, 2).unwrap_or(0) as u32,
+                apic_id: read::<u8>(entry, 3).unwrap_or(0) as u32,
+                enabled: read::<u32>(entry, 4).unwrap_or(0) & 1 != 0,
+            }),
+            // I/O APIC
+            1 => madt.ioapics.push(IoApicEntry {
+                id: read(entry, 2).unwrap_or(0),
+                address: read::<u32>(entry, 4).unwrap_or(0) as u64,
+                gsi_base: read(entry, 8).unwrap_or(0),
+            }),
+            // interrupt source override
+            2 => madt.overrides.push(InterruptOverride {
+                bus: read(entry, 2).unwrap_or(0),
+                irq: read(entry, 3).unwrap_or(0),
+                gsi: read(entry, 4).unwrap_or(0),
+                flags: read(entry, 8).unwrap_or(0),
+            }),
+            // local APIC address override
+            5 => madt.local_apic_address = read(entry, 4).unwrap_or(madt.local_apic_address),
+            // processor local x2APIC
+            9 => madt.processors.push(Processor {
+                acpi_id: read(entry, 12).unwrap_or(0),
+                apic_id: read(entry, 4).unwrap_or(0),
+                enabled: read::<u32>(entry, 8).unwrap_or(0) & 1 != 0,
+            }),
+            _ => {}
+        }
+
+        offset += length;
+    }
+
+    madt
+}
+
+fn parse_hpet(table: &[u8]) -> Option<Hpet> {
+    let block_id: u32 = read(table, 36)?;
+
+    Some(Hpet {
+        address: read(table, 40)?,
+        hpet_number: read(table, 52)?,
+        minimum_tick: read(table, 53)?,
+        comparators: ((block_id >> 8) & 0x1f) as u8 + 1,
+        counter_64bit: block_id & (1 << 13) != 0,
+        vendor_id: (block_id >> 16) as u16,
+    })
+}
+
+fn parse_fadt(table: &[u8]) -> Fadt {
+    const RESET_REG_SUP: u32 = 1 << 10;
+
+    let flags = read(table, 112).unwrap_or(0);
+    let dsdt = match read::<u64>(table, 140) {
+        Some(x_dsdt) if x_dsdt != 0 => x_dsdt,
+        _ => read::<u32>(table, 40).unwrap_or(0) as u64,
+    };
+
+    Fadt {
+        dsdt,
+        sci_interrupt: read(table, 46).unwrap_or(0),
+        smi_command: read(table, 48).unwrap_or(0),
+        acpi_enable: read(table, 52).unwrap_or(0),
+        acpi_disable: read(table, 53).unwrap_or(0),
+        pm1a_control_block: read(table, 64).unwrap_or(0),
+        pm1b_control_block: read(table, 68).unwrap_or(0),
+        pm_timer_block: read(table, 76).unwrap_or(0),
+        boot_architecture_flags: read(table, 109).unwrap_or(0),
+        flags,
+        reset_register: read(table, 116).filter(|_| flags & RESET_REG_SUP != 0),
+        reset_value: read(table, 128).unwrap_or(0),
+    }
+}
+
+fn parse() -> Result<AcpiTables, AcpiError> {
+    let rsdp_phys = boot::info().rsdp.ok_or(AcpiError::NoRsdp)?;
+
+    let rsdp = map_physical(rsdp_phys, RSDP_V1_SIZE)?;
+    if &rsdp[..8] != b"RSD PTR " {
+        return Err(AcpiError::BadRsdpSignature);
+    }
+    if !checksum_ok(rsdp) {
+        return Err(AcpiError::BadRsdpChecksum);
+    }
+
+    // ACPI 2.0 and later have the 64 bit XSDT, whose part of the RSDP has its own checksum.
+    let revision: u8 = read(rsdp, RSDP_REVISION).unwrap();
+    let (root, entry_size) = if revision >= 2 {
+        let length: u32 = read(map_physical(rsdp_phys, RSDP_LENGTH + 4)?, RSDP_LENGTH).unwrap();
+        if (length as usize) < RSDP_XSDT_ADDRESS + 8 {
+            return Err(AcpiError::BadRsdpLength(length));
+        }
+        let rsdp = map_physical(rsdp_phys, length as usize)?;
+        if !checksum_ok(rsdp) {
+            return Err(AcpiError::BadRsdpChecksum);
+        }
+        (map_table(read(rsdp, RSDP_XSDT_ADDRESS).unwrap())?, 8)
+    } else {
+        let rsdt_address: u32 = read(rsdp, RSDP_RSDT_ADDRESS).unwrap();
+        (map_table(rsdt_address as u64)?, 4)
+    };
+
+    let mut tables = AcpiTables {
+        revision,
+        madt: None,
+        hpet: None,
+        fadt: None,
+    };
+
+    for phys in root_entries(root, entry_size) {
+        let table = match map_table(phys) {
+            Ok(table) => table,
+            Err(e) => {
+                kprintln!("acpi: skipping the table at {:#x}: {:?}", phys, e);
+                continue;
+            }
+        };
+
+        match &table[..4] {
+            b"APIC" if tables.madt.is_none() => tables.madt = Some(parse_madt(table)),
+            b"HPET" if tables.hpet.is_none() => tables.hpet = parse_hpet(table),
+            b"FACP" if tables.fadt.is_none() => tables.fadt = Some(parse_fadt(table)),
+            _ => {}
+        }
+    }
+
+    Ok(tables)
+}
+
+/// Finds and parses the ACPI tables. Needs the heap, and must run before `boot::reclaim`.
+pub fn init() {
+    match parse() {
+        Ok(tables) => {
+            if let Some(madt) = &tables.madt {
+                kprintln!(
+                    "acpi: revision {}, {} processors, {} I/O APICs, {} interrupt overrides",
+                    tables.revision,
+                    madt.processors.len(),
+                    madt.ioapics.len(),
+                    madt.overrides.len()
+                );
+            }
+            TABLES.store(Box::into_raw(Box::new(tables)), Ordering::Release);
+        }
+        Err(e) => kprintln!("acpi: no usable tables: {:?}", e),
+    }
+}
+
+/// The tables found by `init`, None if there were none.
+pub fn tables() -> Option<&'static AcpiTables> {
+    unsafe { TABLES.load(Ordering::Acquire).as_ref() }
+}
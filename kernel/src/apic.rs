@@ -0,0 +1,377 @@
+#![allow(dead_code)]
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use crate::acpi;
+use crate::cpu;
+use crate::idt;
+use crate::kprint::{inb, outb};
+use crate::kprintln;
+use crate::pmm::PMM;
+use crate::sync::Spinlock;
+use crate::vmm;
+
+// IA32_APIC_BASE bits
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+// where the I/O APIC sits on practically every PC, for when there is no MADT.
+const DEFAULT_IOAPIC_ADDRESS: u64 = 0xfec0_0000;
+
+// every interrupt the local APIC cannot deliver properly ends up here, and needs no EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+struct LapicRegister {}
+
+impl LapicRegister {
+    const ID: u64 = 0x20;
+    const TPR: u64 = 0x80; // task priority
+    const EOI: u64 = 0xb0;
+    const SVR: u64 = 0xf0; // spurious interrupt vector
+    const ESR: u64 = 0x280; // error status
+    const ICR_LOW: u64 = 0x300; // interrupt command, writing it sends the IPI
+    const ICR_HIGH: u64 = 0x310; // destination in bits 24..31
+    const LVT_TIMER: u64 = 0x320;
+    const LVT_LINT0: u64 = 0x350;
+    const LVT_LINT1: u64 = 0x360;
+    const LVT_ERROR: u64 = 0x370;
+    const TIMER_INITIAL_COUNT: u64 = 0x380;
+    const TIMER_CURRENT_COUNT: u64 = 0x390;
+    const TIMER_DIVIDE: u64 = 0x3e0;
+}
+
+const SVR_APIC_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+
+// virtual address of the local APIC registers, 0 while we are still on the 8259.
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+fn lapic_read(register: u64) -> u32 {
+    let base = LAPIC_BASE.load(Ordering::Relaxed);
+    unsafe { ptr::read_volatile((base + register) as *const u32) }
+}
+
+fn lapic_write(register: u64, value: u32) {
+    let base = LAPIC_BASE.load(Ordering::Relaxed);
+    unsafe { ptr::write_volatile((base + register) as *mut u32, value) };
+}
+
+/// Whether interrupts are delivered through the APICs instead of the legacy PIC.
+pub fn is_enabled() -> bool {
+    LAPIC_BASE.load(Ordering::Relaxed) != 0
+}
+
+/// The local APIC id of the calling CPU.
+pub fn lapic_id() -> u32 {
+    lapic_read(LapicRegister::ID) >> 24
+}
+
+pub fn lapic_end_of_interrupt() {
+    lapic_write(LapicRegister::EOI, 0);
+}
+
+/// Sends a fixed IPI with `vector` to the CPU whose local APIC has the id `lapic_id`. Must run
+/// with interrupts off, another IPI sent in between would change the destination.
+pub fn send_ipi(lapic_id: u32, vector: u8) {
+    lapic_write(LapicRegister::ICR_HIGH, lapic_id << 24);
+    lapic_write(LapicRegister::ICR_LOW, vector as u32);
+    while lapic_read(LapicRegister::ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+fn lapic_init(base: u64) {
+    LAPIC_BASE.store(base, Ordering::Relaxed);
+
+    // nothing comes in through the local interrupt pins or the timer until someone asks for it.
+    lapic_write(LapicRegister::LVT_TIMER, LVT_MASKED);
+    lapic_write(LapicRegister::LVT_LINT0, LVT_MASKED);
+    lapic_write(LapicRegister::LVT_LINT1, LVT_MASKED);
+    lapic_write(LapicRegister::LVT_ERROR, LVT_MASKED);
+
+    // the ESR has to be written before it can be read.
+    lapic_write(LapicRegister::ESR, 0);
+    lapic_write(LapicRegister::ESR, 0);
+
+    lapic_write(LapicRegister::TPR, 0); // accept every priority
+    lapic_write(LapicRegister::SVR, SVR_APIC_ENABLE | SPURIOUS_VECTOR as u32);
+    lapic_end_of_interrupt();
+}
+
+/// Enables the local APIC of an AP, with the same setup as the BSP's. Does nothing if the BSP
+/// stayed on the 8259.
+pub fn init_ap() {
+    if !is_enabled() {
+        return;
+    }
+
+    let apic_base = unsafe { cpu::rdmsr(cpu::Msr::IA32_APIC_BASE) };
+    unsafe { cpu::wrmsr(cpu::Msr::IA32_APIC_BASE, apic_base | APIC_BASE_ENABLE) };
+    lapic_init(LAPIC_BASE.load(Ordering::Relaxed));
+}
+
+// PIT channel 2, whose gate and output are wired to port 0x61 instead of an IRQ line.
+const PIT_CHANNEL_2_DATA: i16 = 0x42;
+const PIT_COMMAND: i16 = 0x43;
+const PIT_CHANNEL_2_GATE: i16 = 0x61;
+
+// local APIC timer ticks (divided by 16) per timer period, the same on every CPU.
+static TIMER_PERIOD: AtomicU32 = AtomicU32::new(0);
+
+/// Measures how fast the local APIC timer counts against one period of a `hz` timer on the PIT,
+/// whose frequency is known. Has to run before `start_timer`.
+pub fn calibrate_timer(hz: u32) {
+    let count = idt::PIT_BASE_FREQUENCY / hz;
+
+    let elapsed = unsafe {
+        // gate on, speaker off.
+        let gate = inb(PIT_CHANNEL_2_GATE) as u8;
+        outb(PIT_CHANNEL_2_GATE, ((gate & !0b10) | 0b01) as i8);
+
+        // channel 2, lobyte/hibyte access, mode 0 (output goes high at the end of the count)
+        outb(PIT_COMMAND, 0xb0_u8 as i8);
+        outb(PIT_CHANNEL_2_DATA, (count & 0xff) as u8 as i8);
+        outb(PIT_CHANNEL_2_DATA, ((count >> 8) & 0xff) as u8 as i8);
+
+        lapic_write(LapicRegister::TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        lapic_write(LapicRegister::TIMER_INITIAL_COUNT, u32::MAX);
+
+        while inb(PIT_CHANNEL_2_GATE) as u8 & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+
+        let elapsed = u32::MAX - lapic_read(LapicRegister::TIMER_CURRENT_COUNT);
+        lapic_write(LapicRegister::TIMER_INITIAL_COUNT, 0);
+        outb(PIT_CHANNEL_2_GATE, gate as i8);
+        elapsed
+    };
+
+    TIMER_PERIOD.store(elapsed, Ordering::Relaxed);
+    kprintln!(
+        "apic: timer runs at {} kHz",
+        elapsed as u64 * 16 * hz as u64 / 1000
+    );
+}
+
+/// Starts the calling CPU's local APIC timer, raising `vector` at the rate it was calibrated to.
+pub fn start_timer(vector: u8) {
+    let period = TIMER_PERIOD.load(Ordering::Relaxed);
+    if period == 0 {
+        kprintln!("apic: the timer was never calibrated, not starting it");
+        return;
+    }
+
+    lapic_write(LapicRegister::TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    lapic_write(LapicRegister::LVT_TIMER, LVT_TIMER_PERIODIC | vector as u32);
+    lapic_write(LapicRegister::TIMER_INITIAL_COUNT, period);
+}
+
+struct IoApicRegister {}
+
+impl IoApicRegister {
+    const REGSEL: u64 = 0x00;
+    const WIN: u64 = 0x10;
+
+    const VERSION: u32 = 0x01;
+    const REDIRECTION_TABLE: u32 = 0x10; // two 32 bit registers per entry
+}
+
+pub struct RedirectionFlags {}
+
+impl RedirectionFlags {
+    pub const ACTIVE_LOW: u64 = 1 << 13;
+    pub const LEVEL_TRIGGERED: u64 = 1 << 15;
+    pub const MASKED: u64 = 1 << 16;
+}
+
+pub struct IoApic {
+    base: u64,     // virtual address of the registers
+    gsi_base: u32, // first global system interrupt handled by this I/O APIC
+    entries: u32,  // number of redirection entries
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile((self.base + IoApicRegister::REGSEL) as *mut u32, register);
+            ptr::read_volatile((self.base + IoApicRegister::WIN) as *const u32)
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile((self.base + IoApicRegister::REGSEL) as *mut u32, register);
+            ptr::write_volatile((self.base + IoApicRegister::WIN) as *mut u32, value);
+        }
+    }
+
+    fn new(base: u64, gsi_base: u32) -> IoApic {
+        let mut ioapic = IoApic {
+            base,
+            gsi_base,
+            entries: 0,
+        };
+        ioapic.entries = ((ioapic.read(IoApicRegister::VERSION) >> 16) & 0xff) + 1;
+
+        for index in 0..ioapic.entries {
+            ioapic.set_entry(index, RedirectionFlags::MASKED);
+        }
+        ioapic
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+
+    fn set_entry(&self, index: u32, entry: u64) {
+        let register = IoApicRegister::REDIRECTION_TABLE + 2 * index;
+
+        // mask it first so it never fires half written.
+        self.write(register, RedirectionFlags::MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+}
+
+/// How an ISA IRQ is wired to the I/O APIC, for the IRQs where it is not the identity.
+#[derive(Clone, Copy)]
+pub struct IsaOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub flags: u64, // RedirectionFlags::ACTIVE_LOW and/or LEVEL_TRIGGERED
+}
+
+impl IsaOverride {
+    /// Converts a MADT interrupt source override, whose polarity and trigger mode default to
+    /// the ISA ones (active high, edge triggered) unless set to 0b11.
+    fn from_madt(o: &acpi::InterruptOverride) -> IsaOverride {
+        let mut flags = 0;
+        if o.flags & 0b11 == 0b11 {
+            flags |= RedirectionFlags::ACTIVE_LOW;
+        }
+        if (o.flags >> 2) & 0b11 == 0b11 {
+            flags |= RedirectionFlags::LEVEL_TRIGGERED;
+        }
+        IsaOverride {
+            irq: o.irq,
+            gsi: o.gsi,
+            flags,
+        }
+    }
+}
+
+struct Routing {
+    ioapics: Vec<IoApic>,
+    overrides: [Option<IsaOverride>; 16],
+}
+
+static ROUTING: Spinlock<Routing> = Spinlock::new(Routing {
+    ioapics: Vec::new(),
+    overrides: [None; 16],
+});
+
+// the PIT on IRQ0 is wired to pin 2 on virtually every chipset, for when there is no MADT.
+const DEFAULT_OVERRIDES: [IsaOverride; 1] = [IsaOverride {
+    irq: 0,
+    gsi: 2,
+    flags: 0,
+}];
+
+/// Routes the ISA IRQ `irq` to `vector` on the CPU with the local APIC id `destination`.
+pub fn route_isa_irq(irq: u8, vector: u8, destination: u32) {
+    let routing = ROUTING.lock();
+    let (gsi, flags) = match routing.overrides[irq as usize] {
+        Some(o) => (o.gsi, o.flags),
+        None => (irq as u32, 0), // ISA default: edge triggered, active high
+    };
+
+    let Some(ioapic) = routing.ioapics.iter().find(|ioapic| ioapic.handles(gsi)) else {
+        kprintln!(
+            "apic: IRQ {} maps to GSI {} which no I/O APIC handles",
+            irq,
+            gsi
+        );
+        return;
+    };
+
+    let entry = ((destination as u64) << 56) | flags | vector as u64;
+    ioapic.set_entry(gsi - ioapic.gsi_base, entry);
+}
+
+/// Switches interrupt delivery from the 8259 to the local and I/O APICs if the CPU has them, and
+/// routes the ISA IRQs to `isa_vector_base` onwards. Takes the I/O APICs and the ISA wiring from
+/// the MADT, PC defaults without one. Returns whether it did. Needs the VMM and the heap.
+pub fn init(isa_vector_base: u8, madt: Option<&acpi::Madt>) -> bool {
+    if !cpu::has_apic() {
+        kprintln!("apic: not available, staying on the 8259");
+        return false;
+    }
+
+    let apic_base = unsafe { cpu::rdmsr(cpu::Msr::IA32_APIC_BASE) };
+    unsafe { cpu::wrmsr(cpu::Msr::IA32_APIC_BASE, apic_base | APIC_BASE_ENABLE) };
+
+    let ioapics: Vec<(u64, u32)> = match madt {
+        Some(madt) if !madt.ioapics.is_empty() => madt
+            .ioapics
+            .iter()
+            .map(|ioapic| (ioapic.address, ioapic.gsi_base))
+            .collect(),
+        _ => Vec::from([(DEFAULT_IOAPIC_ADDRESS, 0)]),
+    };
+    // allocated up front, the heap must not be touched while holding the PMM.
+    let mut mapped = Vec::with_capacity(ioapics.len());
+
+    let mut pmm = PMM.lock();
+    let lapic = vmm::map_mmio(apic_base & APIC_BASE_ADDRESS_MASK, &mut pmm);
+    for &(address, gsi_base) in &ioapics {
+        if let Ok(base) = vmm::map_mmio(address, &mut pmm) {
+            mapped.push((base, gsi_base));
+        }
+    }
+    drop(pmm);
+
+    let (Ok(lapic), true) = (lapic, mapped.len() == ioapics.len()) else {
+        kprintln!("apic: could not map the registers, staying on the 8259");
+        return false;
+    };
+
+    lapic_init(lapic);
+
+    let mut routing = ROUTING.lock();
+    routing.ioapics = mapped
+        .iter()
+        .map(|&(base, gsi_base)| IoApic::new(base, gsi_base))
+        .collect();
+    match madt {
+        Some(madt) => {
+            for o in madt.overrides.iter().filter(|o| o.bus == 0 && o.irq < 16) {
+                routing.overrides[o.irq as usize] = Some(IsaOverride::from_madt(o));
+            }
+        }
+        None => {
+            for o in DEFAULT_OVERRIDES {
+                routing.overrides[o.irq as usize] = Some(o);
+            }
+        }
+    }
+    drop(routing);
+
+    // IRQ2 is only the cascade between the two 8259s, it never fires.
+    let destination = lapic_id();
+    for irq in (0..16).filter(|&irq| irq != 2) {
+        route_isa_irq(irq, isa_vector_base + irq, destination);
+    }
+
+    kprintln!(
+        "apic: local APIC {} at {:#x}, {} I/O APIC(s) starting at {:#x}",
+        destination,
+        apic_base & APIC_BASE_ADDRESS_MASK,
+        ioapics.len(),
+        ioapics[0].0
+    );
+    true
+}
@@ -1,6 +1,11 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use limine::request::{
     FramebufferRequest, HhdmRequest, KernelAddressRequest, KernelFileRequest, MemoryMapRequest,
-    ModuleRequest, RequestsEndMarker, RequestsStartMarker,
+    ModuleRequest, RequestsEndMarker, RequestsStartMarker, RsdpRequest, SmpRequest,
+};
+use limine::response::{
+    FramebufferResponse, HhdmResponse, MemoryMapResponse, ModuleResponse, RsdpResponse, SmpResponse,
 };
 use limine::BaseRevision;
 /// Sets the base revision to the latest revision supported by the crate.
@@ -13,27 +18,35 @@ pub static BASE_REVISION: BaseRevision = BaseRevision::new();
 
 #[used]
 #[link_section = ".requests"]
-pub static FRAMEBUFFER_REQUEST: FramebufferRequest = FramebufferRequest::new();
+static FRAMEBUFFER_REQUEST: FramebufferRequest = FramebufferRequest::new();
+
+#[used]
+#[link_section = ".requests"]
+static MEMMAP_REQUEST: MemoryMapRequest = MemoryMapRequest::new();
+
+#[used]
+#[link_section = ".requests"]
+static KERNEL_ADDRESS_REQUEST: KernelAddressRequest = KernelAddressRequest::new();
 
 #[used]
 #[link_section = ".requests"]
-pub static MEMMAP_REQUEST: MemoryMapRequest = MemoryMapRequest::new();
+static HHDM_REQUEST: HhdmRequest = HhdmRequest::new();
 
 #[used]
 #[link_section = ".requests"]
-pub static KERNEL_ADDRESS_REQUEST: KernelAddressRequest = KernelAddressRequest::new();
+static KFILE_REQUEST: KernelFileRequest = KernelFileRequest::new();
 
 #[used]
 #[link_section = ".requests"]
-pub static HHDM_REQUEST: HhdmRequest = HhdmRequest::new();
+static MODULE_REQUEST: ModuleRequest = ModuleRequest::new();
 
 #[used]
 #[link_section = ".requests"]
-pub static KFILE_REQUEST: KernelFileRequest = KernelFileRequest::new();
+static RSDP_REQUEST: RsdpRequest = RsdpRequest::new();
 
 #[used]
 #[link_section = ".requests"]
-pub static MODULE_REQUEST: ModuleRequest = ModuleRequest::new();
+static SMP_REQUEST: SmpRequest = SmpRequest::new();
 
 /// Define the stand and end markers for Limine requests.
 #[used]
@@ -43,3 +56,47 @@ static _START_MARKER: RequestsStartMarker = RequestsStartMarker::new();
 #[link_section = ".requests_end_marker"]
 static _END_MARKER: RequestsEndMarker = RequestsEndMarker::new();
 
+// the responses live in bootloader reclaimable memory, which the PMM may hand out once this is set.
+static RETIRED: AtomicBool = AtomicBool::new(false);
+
+fn check_alive() {
+    assert!(
+        !RETIRED.load(Ordering::Acquire),
+        "the bootloader's responses were already reclaimed!"
+    );
+}
+
+/// Forbids any further access to the responses, `boot::reclaim` is about to free them.
+pub fn retire() {
+    RETIRED.store(true, Ordering::Release);
+}
+
+pub fn framebuffer() -> Option<&'static FramebufferResponse> {
+    check_alive();
+    FRAMEBUFFER_REQUEST.get_response()
+}
+
+pub fn memmap() -> Option<&'static MemoryMapResponse> {
+    check_alive();
+    MEMMAP_REQUEST.get_response()
+}
+
+pub fn hhdm() -> Option<&'static HhdmResponse> {
+    check_alive();
+    HHDM_REQUEST.get_response()
+}
+
+pub fn modules() -> Option<&'static ModuleResponse> {
+    check_alive();
+    MODULE_REQUEST.get_response()
+}
+
+pub fn rsdp() -> Option<&'static RsdpResponse> {
+    check_alive();
+    RSDP_REQUEST.get_response()
+}
+
+pub fn smp() -> Option<&'static SmpResponse> {
+    check_alive();
+    SMP_REQUEST.get_response()
+}
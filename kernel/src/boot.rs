@@ -0,0 +1,177 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use limine::memory_map::EntryType;
+
+use crate::asa_limine;
+use crate::kprintln;
+use crate::pmm::{FRAME_SIZE, PMM};
+use crate::smp;
+
+pub const MAX_MEMORY_REGIONS: usize = 256;
+pub const MAX_MODULES: usize = 16;
+
+#[derive(Clone, Copy)]
+pub struct MemoryRegion {
+    pub base: u64,
+    pub length: u64,
+    pub kind: EntryType,
+}
+
+/// A file Limine loaded next to the kernel. Its contents live in memory that is never reclaimed.
+#[derive(Clone, Copy)]
+pub struct Module {
+    pub addr: *mut u8,
+    pub size: u64,
+}
+
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+pub struct Framebuffer {
+    pub addr: *mut u8,
+    pub width: u64,
+    pub height: u64,
+    pub pitch: u64,
+    pub bpp: u16,
+}
+
+/// Everything the kernel keeps from the bootloader's responses, copied out of them before the
+/// memory they live in goes back to the PMM.
+pub struct BootInfo {
+    memmap: [MemoryRegion; MAX_MEMORY_REGIONS],
+    memmap_len: usize,
+    modules: [Module; MAX_MODULES],
+    modules_len: usize,
+    pub framebuffer: Option<Framebuffer>,
+    pub rsdp: Option<u64>, // physical address
+}
+
+impl BootInfo {
+    pub fn memmap(&self) -> &[MemoryRegion] {
+        &self.memmap[..self.memmap_len]
+    }
+
+    pub fn modules(&self) -> &[Module] {
+        &self.modules[..self.modules_len]
+    }
+}
+
+// written once by `init` before anything reads it, there is no heap to put it on yet.
+static mut INFO: BootInfo = BootInfo {
+    memmap: [MemoryRegion {
+        base: 0,
+        length: 0,
+        kind: EntryType::RESERVED,
+    }; MAX_MEMORY_REGIONS],
+    memmap_len: 0,
+    modules: [Module {
+        addr: core::ptr::null_mut(),
+        size: 0,
+    }; MAX_MODULES],
+    modules_len: 0,
+    framebuffer: None,
+    rsdp: None,
+};
+
+static HHDM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Copies the memory map, the modules, the first framebuffer, the RSDP address and the HHDM
+/// offset out of the bootloader's responses. Must run before anything else needs them.
+pub fn init() {
+    let hhdm = asa_limine::hhdm().unwrap().offset();
+    HHDM_OFFSET.store(hhdm, Ordering::Relaxed);
+
+    let info = &raw mut INFO;
+    let info = unsafe { &mut *info };
+
+    let entries = asa_limine::memmap().unwrap().entries();
+    if entries.len() > MAX_MEMORY_REGIONS {
+        kprintln!(
+            "boot: only keeping the first {} memory map entries",
+            MAX_MEMORY_REGIONS
+        );
+    }
+    for (region, entry) in info.memmap.iter_mut().zip(entries) {
+        *region = MemoryRegion {
+            base: entry.base,
+            length: entry.length,
+            kind: entry.entry_type,
+        };
+        info.memmap_len += 1;
+    }
+
+    if let Some(response) = asa_limine::modules() {
+        for (module, file) in info.modules.iter_mut().zip(response.modules()) {
+            *module = Module {
+                addr: file.addr(),
+                size: file.size(),
+            };
+            info.modules_len += 1;
+        }
+    }
+
+    info.framebuffer = asa_limine::framebuffer()
+        .and_then(|response| response.framebuffers().next())
+        .map(|framebuffer| Framebuffer {
+            addr: framebuffer.addr(),
+            width: framebuffer.width(),
+            height: framebuffer.height(),
+            pitch: framebuffer.pitch(),
+            bpp: framebuffer.bpp(),
+        });
+
+    // base revision 2 hands out the HHDM address of the RSDP, later ones the physical one.
+    info.rsdp = asa_limine::rsdp().map(|response| match response.address() as u64 {
+        address if address >= hhdm => address - hhdm,
+        address => address,
+    });
+}
+
+pub fn info() -> &'static BootInfo {
+    let info = &raw const INFO;
+    unsafe { &*info }
+}
+
+/// Offset of the higher half direct map, where all of physical memory is mapped.
+pub fn hhdm_offset() -> u64 {
+    HHDM_OFFSET.load(Ordering::Relaxed)
+}
+
+/// Declares the bootloader's responses dead and hands the memory they, Limine's page tables
+/// and stacks and the ACPI tables live in to the PMM. From here on nothing may touch any of
+/// them: the APs must be running on their own stacks, the ACPI tables must be parsed.
+pub fn reclaim() {
+    asa_limine::retire();
+
+    let keep_bootloader_memory = smp::aps_parked();
+    if keep_bootloader_memory {
+        kprintln!("boot: keeping the bootloader's memory, some APs still spin in it");
+    }
+
+    let mut pmm = PMM.lock();
+    let mut reclaimed = 0;
+
+    for region in info().memmap() {
+        let reclaimable = match region.kind {
+            EntryType::BOOTLOADER_RECLAIMABLE => !keep_bootloader_memory,
+            EntryType::ACPI_RECLAIMABLE => true,
+            _ => false,
+        };
+        if !reclaimable {
+            continue;
+        }
+
+        // only usable and bootloader reclaimable entries are page aligned, and the null frame
+        // is never handed out.
+        let base = region
+            .base
+            .max(FRAME_SIZE as u64)
+            .next_multiple_of(FRAME_SIZE as u64);
+        let end = (region.base + region.length) & !(FRAME_SIZE as u64 - 1);
+        if end > base {
+            pmm.reclaim(base, (end - base) as usize, region.kind);
+            reclaimed += end - base;
+        }
+    }
+
+    kprintln!("boot: reclaimed {:#x} bytes of boot data", reclaimed);
+}
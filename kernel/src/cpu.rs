@@ -5,11 +5,12 @@ use crate::pmm;
 pub struct Msr {}
 
 impl Msr {
+    pub const IA32_APIC_BASE: u32 = 0x1B;
     pub const IA32_EFER: u32 = 0xC0000080;
     pub const IA32_STAR: u32 = 0xC0000081;
     pub const IA32_LSTAR: u32 = 0xC0000082;
     pub const IA32_FSTAR: u32 = 0xC0000084;
-    pub const IA32_USER_GS_BASE: u32 = 0xC0000101;
+    pub const IA32_GS_BASE: u32 = 0xC0000101; // the active one, swapgs exchanges it with the next
     pub const IA32_KERNEL_GS_BASE: u32 = 0xC0000102;
 }
 
@@ -50,5 +51,28 @@ pub unsafe fn cr3() -> pmm::Frame  {
 
     asm!("mov rax, cr3", out("rax")(cr3));
 
-    return pmm::Frame::from_u64(cr3, pmm::FRAME_SIZE);
+    // the low 12 bits hold PWT/PCD (or the PCID), not part of the address.
+    return pmm::Frame::from_u64(cr3 & !0xfff, pmm::FRAME_SIZE);
+}
+
+pub unsafe fn set_cr3(page_table: &pmm::Frame) {
+    asm!("mov cr3, {}", in(reg) page_table.phy_ptr(), options(nostack, preserves_flags));
+}
+
+/// Cycles since reset, counted by the time stamp counter.
+pub fn rdtsc() -> u64 {
+    let low: u32;
+    let high: u32;
+
+    unsafe {
+        asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack));
+    }
+
+    ((high as u64) << 32) | low as u64
+}
+
+/// Whether the CPU has a local APIC (CPUID.01h:EDX bit 9).
+pub fn has_apic() -> bool {
+    let cpuid = unsafe { core::arch::x86_64::__cpuid(1) };
+    cpuid.edx & (1 << 9) != 0
 }
@@ -1,7 +1,12 @@
 #![allow(dead_code)]
 // use core::assert;
+use core::fmt;
+use core::ops::Range;
 use core::slice;
 
+use crate::pmm::{Frame, Pmm, PmmAllocError, FRAME_SIZE};
+use crate::vmm::{self, PageFlags, VmmError};
+
 type Elf64Off = u64;
 type Elf64Addr = u64;
 type Elf64Xword = u64;
@@ -124,7 +129,7 @@ enum ShFlags {
 #[repr(C, packed)]
 struct Elf64Ehdr {
     e_ident: [u8; EIdent::EiNident as usize], /* ELF identification */
-    e_type: ElfType,                          /* Object file type */
+    e_type: Elf64Half,                        /* Object file type */
     e_machine: Elf64Half,                     /* Machine type */
     e_version: Elf64Word,                     /* Object file version */
     e_entry: Elf64Addr,                       /* Entry point address */
@@ -167,110 +172,691 @@ struct Elf64Phdr {
     p_align: Elf64Xword,  /* Alignment of segment */
 }
 
+// Dynamic array tags, d_tag
+#[derive(Debug)]
+#[repr(i64)]
+#[allow(
+    clippy::enum_variant_names,
+    reason = "the variants mirror the DT_* names of the ELF specification"
+)]
+enum DynTag {
+    DtNull = 0,     // Marks the end of the dynamic array
+    DtNeeded = 1,   // The string table offset of the name of a needed library
+    DtRela = 7,     // Address of a relocation table with Elf64_Rela entries
+    DtRelasz = 8,   // Total size, in bytes, of the DT_RELA relocation table
+    DtRelaent = 9,  // Size, in bytes, of each DT_RELA relocation entry
+    DtRel = 17,     // Address of a relocation table with Elf64_Rel entries
+    DtTextrel = 22, // The relocation table contains relocations for a non-writable segment
+}
+
+// Relocation types, ELF64_R_TYPE(r_info). Only the ones a static PIE needs.
+#[derive(Debug)]
+#[repr(u32)]
+enum RelocType {
+    RX86_64None = 0,     // No reloc
+    RX86_64_64 = 1,      // Direct 64 bit: S + A
+    RX86_64GlobDat = 6,  // Create GOT entry: S
+    RX86_64Relative = 8, // Adjust by program base: B + A
+}
+
+#[derive(Debug)]
+#[repr(C, packed)]
+struct Elf64Dyn {
+    d_tag: i64, /* Dynamic entry type */
+    d_val: u64, /* Integer or address value */
+}
+
+#[derive(Debug)]
+#[repr(C, packed)]
+struct Elf64Rela {
+    r_offset: Elf64Addr, /* Address of reference */
+    r_info: Elf64Xword,  /* Symbol index and type of relocation */
+    r_addend: i64,       /* Constant part of expression */
+}
+
 #[derive(Debug)]
 #[repr(C, packed)]
+struct Elf64Sym {
+    st_name: Elf64Word,  /* Symbol name */
+    st_info: u8,         /* Type and Binding attributes */
+    st_other: u8,        /* Reserved */
+    st_shndx: Elf64Half, /* Section table index */
+    st_value: Elf64Addr, /* Symbol value */
+    st_size: Elf64Xword, /* Size of object (e.g., common) */
+}
+
+// Symbol types, ELF64_ST_TYPE(st_info)
+#[derive(Debug)]
+#[repr(u8)]
+#[allow(
+    clippy::enum_variant_names,
+    reason = "the variants mirror the STT_* names of the ELF specification"
+)]
+enum SymType {
+    SttNotype = 0,  // No type specified (e.g., an absolute symbol)
+    SttObject = 1,  // Data object
+    SttFunc = 2,    // Function entry point
+    SttSection = 3, // Symbol is associated with a section
+    SttFile = 4,    // Source file associated with the object file
+}
+
+// section index of symbols that are not defined in this file.
+const SHN_UNDEF: Elf64Half = 0;
+// section index of symbols whose value is an absolute address, unaffected by the load bias.
+const SHN_ABS: Elf64Half = 0xfff1;
+
 pub struct Elf64<'a> {
+    buf: &'a [u8],
     header: Elf64Ehdr,
     sheaders: &'a [Elf64Shdr],
     pheaders: &'a [Elf64Phdr],
 }
 
-pub fn parse<'a>(buf: *mut u8, len: u64) -> Elf64<'a> {
+impl fmt::Debug for Elf64<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Elf64")
+            .field("len", &self.buf.len())
+            .field("header", &self.header)
+            .field("sheaders", &self.sheaders)
+            .field("pheaders", &self.pheaders)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub enum ElfError {
+    TooShort,
+    BadMagic,
+    UnsupportedClass(u8),
+    UnsupportedEndianness(u8),
+    UnsupportedAbi(u8),
+    UnsupportedType(u16),
+    MissingProgramHeaders,
+    MissingSectionHeaders,
+    BadPhentsize(u16),
+    BadShentsize(u16),
+    PhoffOutOfBounds,
+    ShoffOutOfBounds,
+    TruncatedProgramHeaders,
+    TruncatedSectionHeaders,
+    ShstrndxOutOfBounds(u16),
+    SegmentOutOfFile,
+    SegmentNotInUserSpace,
+    MisalignedLoadBias,
+    BadDynamicSection,
+    NeedsSharedLibrary,
+    BadRelocation,
+    UnsupportedRelocation(u32),
+    UndefinedSymbol(u32),
+    EntryOutsideImage, // e_entry is not in an executable PT_LOAD segment
+    SegmentOverlapsReserved,
+    OutOfMemory,
+}
+
+impl From<PmmAllocError> for ElfError {
+    fn from(_: PmmAllocError) -> Self {
+        ElfError::OutOfMemory
+    }
+}
+
+impl From<VmmError> for ElfError {
+    fn from(e: VmmError) -> Self {
+        match e {
+            VmmError::OutOfMemory => ElfError::OutOfMemory,
+            _ => ElfError::SegmentNotInUserSpace,
+        }
+    }
+}
+
+/// Returns `count` entries of `T` found at `offset` in `buf`, if they fit inside it.
+fn table_at<T>(buf: &[u8], offset: u64, count: u64) -> Option<&[T]> {
+    let size = count.checked_mul(core::mem::size_of::<T>() as u64)?;
+    let end = offset.checked_add(size)?;
+    if end > buf.len() as u64 {
+        return None;
+    }
+
+    // all the ELF structures are packed, so any byte offset is suitably aligned.
+    let ptr = buf[offset as usize..].as_ptr() as *const T;
+    Some(unsafe { slice::from_raw_parts(ptr, count as usize) })
+}
+
+pub fn parse<'a>(buf: *mut u8, len: u64) -> Result<Elf64<'a>, ElfError> {
     let len = len as usize;
     let buf = unsafe { slice::from_raw_parts(buf, len) };
-    
-    let start = buf;
-    let current = start;
 
     let sizeof_elf_header = core::mem::size_of::<Elf64Ehdr>();
-    assert!(len > sizeof_elf_header, "Not an ELF File (length check)!");
-
-    let (header_bytes, _) = current.split_at(sizeof_elf_header);
-    let header = unsafe { (header_bytes.as_ptr() as *const Elf64Ehdr).read_unaligned() };
-
-    assert!(
-        header.e_ident[EIdent::EiMag0 as usize] == b'\x7f'
-            && header.e_ident[EIdent::EiMag1 as usize] == b'E'
-            && header.e_ident[EIdent::EiMag2 as usize] == b'L'
-            && header.e_ident[EIdent::EiMag3 as usize] == b'F',
-        "Not an ELF file (header check)!"
-    );
-    assert!(
-        header.e_ident[EIdent::EiClass as usize] == ElfClass::Elfclass64 as u8,
-        "only ELFCLASS64 supported!"
-    );
-    assert!(
-        header.e_ident[EIdent::EiData as usize] == ElfData::Elfdata2Lsb as u8,
-        "only ELFDATA2LSB supported!"
-    );
-    assert!(
-        header.e_ident[EIdent::EiOsabi as usize] == ElfOSAbi::ElfosabiSysv as u8,
-        "only ELFOSABI_SYSV supported!"
-    );
+    if len < sizeof_elf_header {
+        return Err(ElfError::TooShort);
+    }
+
+    let header = unsafe { (buf.as_ptr() as *const Elf64Ehdr).read_unaligned() };
+
+    if header.e_ident[EIdent::EiMag0 as usize] != b'\x7f'
+        || header.e_ident[EIdent::EiMag1 as usize] != b'E'
+        || header.e_ident[EIdent::EiMag2 as usize] != b'L'
+        || header.e_ident[EIdent::EiMag3 as usize] != b'F'
+    {
+        return Err(ElfError::BadMagic);
+    }
+
+    let class = header.e_ident[EIdent::EiClass as usize];
+    if class != ElfClass::Elfclass64 as u8 {
+        return Err(ElfError::UnsupportedClass(class));
+    }
+
+    let data = header.e_ident[EIdent::EiData as usize];
+    if data != ElfData::Elfdata2Lsb as u8 {
+        return Err(ElfError::UnsupportedEndianness(data));
+    }
+
+    let osabi = header.e_ident[EIdent::EiOsabi as usize];
+    if osabi != ElfOSAbi::ElfosabiSysv as u8 {
+        return Err(ElfError::UnsupportedAbi(osabi));
+    }
 
     let e_type = header.e_type;
-    assert!(
-        e_type == ElfType::EtExec || e_type == ElfType::EtDyn,
-        "we only support ET_EXEC or ET_DYN for now!"
-    );
-    assert!(header.e_phoff > 0, "we expect ph_off to be present!");
-    assert!(header.e_phnum > 0, "we expect ph_num to be present!");
-    assert!(header.e_shoff > 0, "we expect sh_off to be present!");
-    assert!(header.e_shnum > 0, "we expect sh_num to be present!");
-    assert!(
-        header.e_phentsize as usize == core::mem::size_of::<Elf64Phdr>(),
-        "Elf64_Phdr is not of correct size as in the elf file!"
-    );
-    assert!(
-        header.e_shentsize as usize == core::mem::size_of::<Elf64Shdr>(),
-        "Elf64_Shdr is not of correct size as in the elf file!"
-    );
-
-    let (_, pheader_and_rest) = current.split_at(header.e_phoff as usize);
-    let pheader = pheader_and_rest.as_ptr() as *const Elf64Phdr;
-    let pheaders: &[Elf64Phdr] = unsafe { slice::from_raw_parts(pheader, header.e_phnum as usize) };
-
-    let (_, sheader_and_rest) = current.split_at(header.e_shoff as usize);
-    let sheader = sheader_and_rest.as_ptr() as *const Elf64Shdr;
-    let sheaders: &[Elf64Shdr] = unsafe { slice::from_raw_parts(sheader, header.e_shnum as usize) };
-
-
-    // Elf64_Shdr* shstr = sheaders + header.e_shstrndx; // get the sheader at the index specified in the header.
-    // u64 shstroffset = (u64)start + shstr->sh_offset; // get the section offset and add it the start.
-
-    // for (int i = 0; i < header.e_shnum; i++) {
-    //     Elf64_Shdr sheader = sheaders[i];
-    //     u8* name = (u8*)(shstroffset + sheader.sh_name);
-
-    //     // printf("(%d/%d) section:[%s] type: 0x%x name: 0x%x flags: 0x%lx virtual: 0x%lx sh_offset: 0x%lx sh_link: 0x%x\n",
-    //     //  i,
-    //     //  header.e_shnum,
-    //     //  name,
-    //     //  sheader.sh_type,
-    //     //  sheader.sh_name,
-    //     //  sheader.sh_flags,
-    //     //  sheader.sh_addr,
-    //     //  sheader.sh_offset,
-    //     //  sheader.sh_link
-    //     //  );
-    // }
-
-    // printf("diff: 0x%lx\n", (u64) (header_end - start) );
-    // printf("e_entry: 0x%lx\n", header.e_entry );
-    // printf("e_shoff: 0x%lx\n", header.e_shoff );
-    // printf("e_phoff: 0x%lx\n", header.e_phoff );
-
-    /*
-    result.header = header;
-    result.elf_module_start = (u64) buf;
-    result.s_headers = sheaders;
-    result.s_headers_len = header.e_phnum;
-    result.p_headers = pheaders;
-    result.p_headers_len = header.e_shnum;
-     */
-    Elf64 {
-	header,
-	pheaders,
-	sheaders
+    if e_type != ElfType::EtExec as u16 && e_type != ElfType::EtDyn as u16 {
+        return Err(ElfError::UnsupportedType(e_type));
+    }
+
+    if header.e_phoff == 0 || header.e_phnum == 0 {
+        return Err(ElfError::MissingProgramHeaders);
+    }
+    if header.e_shoff == 0 || header.e_shnum == 0 {
+        return Err(ElfError::MissingSectionHeaders);
+    }
+
+    let e_phentsize = header.e_phentsize;
+    if e_phentsize as usize != core::mem::size_of::<Elf64Phdr>() {
+        return Err(ElfError::BadPhentsize(e_phentsize));
+    }
+    let e_shentsize = header.e_shentsize;
+    if e_shentsize as usize != core::mem::size_of::<Elf64Shdr>() {
+        return Err(ElfError::BadShentsize(e_shentsize));
     }
+
+    if header.e_phoff >= len as u64 {
+        return Err(ElfError::PhoffOutOfBounds);
+    }
+    let pheaders: &[Elf64Phdr] = table_at(buf, header.e_phoff, header.e_phnum as u64)
+        .ok_or(ElfError::TruncatedProgramHeaders)?;
+
+    if header.e_shoff >= len as u64 {
+        return Err(ElfError::ShoffOutOfBounds);
+    }
+    let sheaders: &[Elf64Shdr] = table_at(buf, header.e_shoff, header.e_shnum as u64)
+        .ok_or(ElfError::TruncatedSectionHeaders)?;
+
+    // SHN_UNDEF (0) means there is no section name string table.
+    let e_shstrndx = header.e_shstrndx;
+    if e_shstrndx as usize >= sheaders.len() {
+        return Err(ElfError::ShstrndxOutOfBounds(e_shstrndx));
+    }
+
+    Ok(Elf64 {
+        buf,
+        header,
+        pheaders,
+        sheaders,
+    })
+}
+
+#[derive(Debug)]
+pub struct Section<'a> {
+    pub name: &'a str,
+    pub sh_type: u32,
+    pub flags: u64,
+    pub addr: u64,
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol<'a> {
+    pub name: &'a str,
+    pub value: u64,
+    pub size: u64,
+    pub info: u8,
+}
+
+impl<'a> Elf64<'a> {
+    /// Reads the NUL terminated string at `offset` inside the string table section `strtab`.
+    fn str_at(&self, strtab: &Elf64Shdr, offset: u32) -> Option<&'a str> {
+        let table: &[u8] = table_at(self.buf, strtab.sh_offset, strtab.sh_size)?;
+        let bytes = table.get(offset as usize..)?;
+        let len = bytes.iter().position(|&b| b == 0)?;
+
+        core::str::from_utf8(&bytes[..len]).ok()
+    }
+
+    fn section_name(&self, sh: &Elf64Shdr) -> &'a str {
+        let shstrndx = self.header.e_shstrndx as usize;
+        if shstrndx == SHN_UNDEF as usize {
+            return "";
+        }
+
+        self.str_at(&self.sheaders[shstrndx], sh.sh_name)
+            .unwrap_or("")
+    }
+
+    pub fn sections(&self) -> impl Iterator<Item = Section<'a>> + '_ {
+        self.sheaders.iter().map(|sh| Section {
+            name: self.section_name(sh),
+            sh_type: sh.sh_type,
+            flags: sh.sh_flags,
+            addr: sh.sh_addr,
+            offset: sh.sh_offset,
+            size: sh.sh_size,
+        })
+    }
+
+    pub fn section_by_name(&self, name: &str) -> Option<Section<'a>> {
+        self.sections().find(|section| section.name == name)
+    }
+
+    /// Iterates over the entries of both SHT_SYMTAB and SHT_DYNSYM sections, skipping the
+    /// reserved null symbol at index 0 of each table.
+    pub fn symbols(&self) -> impl Iterator<Item = Symbol<'a>> + '_ {
+        self.sheaders
+            .iter()
+            .filter(|sh| {
+                sh.sh_type == ShType::ShtSymtab as u32 || sh.sh_type == ShType::ShtDynsym as u32
+            })
+            .flat_map(move |sh| {
+                let symbols: &[Elf64Sym] = self.section_table(sh).unwrap_or(&[]);
+                let strtab = self.sheaders.get(sh.sh_link as usize);
+
+                symbols.iter().skip(1).map(move |sym| Symbol {
+                    name: strtab
+                        .and_then(|strtab| self.str_at(strtab, sym.st_name))
+                        .unwrap_or(""),
+                    value: sym.st_value,
+                    size: sym.st_size,
+                    info: sym.st_info,
+                })
+            })
+    }
+
+    pub fn symbol_by_name(&self, name: &str) -> Option<Symbol<'a>> {
+        self.symbols().find(|symbol| symbol.name == name)
+    }
+
+    /// Finds the function or object symbol containing `addr` and returns it together with the
+    /// offset of `addr` into it, e.g. for printing `kmain+0x2a` in a backtrace.
+    pub fn symbol_by_address(&self, addr: u64) -> Option<(Symbol<'a>, u64)> {
+        self.symbols()
+            .filter(|symbol| {
+                let sym_type = symbol.info & 0xf;
+                sym_type == SymType::SttFunc as u8 || sym_type == SymType::SttObject as u8
+            })
+            .filter(|symbol| {
+                // zero sized symbols (e.g. hand written assembly labels) only match exactly, and
+                // ones that would wrap around the address space match nothing.
+                let end = symbol.value.checked_add(symbol.size.max(1));
+                addr >= symbol.value && end.is_some_and(|end| addr < end)
+            })
+            .max_by_key(|symbol| symbol.value)
+            .map(|symbol| (symbol, addr - symbol.value))
+    }
+}
+
+// everything below this is the lower (user) half of the canonical address space.
+pub const USER_SPACE_TOP: u64 = 0x0000_8000_0000_0000;
+
+// where position independent executables get placed unless the caller has a better idea.
+pub const PIE_LOAD_BIAS: u64 = 0x40_0000;
+
+#[derive(Debug)]
+pub struct LoadedElf {
+    pub entry: Elf64Addr,
+    pub load_bias: u64,
+    pub page_table: Frame,
+    pub phdr: Elf64Addr, // where the program headers are mapped, 0 if no segment contains them
+    pub phent: u64,
+    pub phnum: u64,
+}
+
+fn page_align_down(addr: u64) -> u64 {
+    addr & !(FRAME_SIZE as u64 - 1)
+}
+
+fn page_align_up(addr: u64) -> u64 {
+    page_align_down(addr + FRAME_SIZE as u64 - 1)
+}
+
+impl<'a> Elf64<'a> {
+    fn section_table<T>(&self, sh: &Elf64Shdr) -> Option<&'a [T]> {
+        let entsize = core::mem::size_of::<T>() as u64;
+        if sh.sh_entsize != entsize {
+            return None;
+        }
+        table_at(self.buf, sh.sh_offset, sh.sh_size / entsize)
+    }
+
+    fn dynamic(&self) -> Result<&[Elf64Dyn], ElfError> {
+        let entsize = core::mem::size_of::<Elf64Dyn>() as u64;
+        match self
+            .pheaders
+            .iter()
+            .find(|ph| ph.p_type == PhType::PtDynamic as u32)
+        {
+            None => Ok(&[]),
+            Some(ph) => table_at(self.buf, ph.p_offset, ph.p_filesz / entsize)
+                .ok_or(ElfError::BadDynamicSection),
+        }
+    }
+
+    fn load_segments(&self) -> impl Iterator<Item = &Elf64Phdr> {
+        self.pheaders
+            .iter()
+            .filter(|ph| ph.p_type == PhType::PtLoad as u32)
+    }
+
+    // The PT_LOAD segment that, placed at `load_bias`, contains all of [vaddr, vaddr + len).
+    fn segment_containing(&self, load_bias: u64, vaddr: u64, len: u64) -> Option<&Elf64Phdr> {
+        let end = vaddr.checked_add(len)?;
+        self.load_segments().find(|ph| {
+            let start = ph.p_vaddr.checked_add(load_bias);
+            let seg_end = start.and_then(|start| start.checked_add(ph.p_memsz));
+            match (start, seg_end) {
+                (Some(start), Some(seg_end)) => {
+                    start <= vaddr && end <= seg_end && seg_end <= USER_SPACE_TOP
+                }
+                _ => false,
+            }
+        })
+    }
+
+    // Two segments may share a page (e.g. the end of .text and the start of .data), in which case
+    // the page gets the union of both permissions.
+    fn page_flags(&self, page: u64) -> u64 {
+        let mut writable = false;
+        let mut executable = false;
+
+        for ph in self.load_segments() {
+            let start = page_align_down(ph.p_vaddr);
+            let end = page_align_up(ph.p_vaddr + ph.p_memsz);
+            if page >= start && page < end {
+                writable |= ph.p_flags & Pflags::PfW as u32 != 0;
+                executable |= ph.p_flags & Pflags::PfX as u32 != 0;
+            }
+        }
+
+        let mut flags = PageFlags::PRESENT | PageFlags::USER;
+        if writable {
+            flags |= PageFlags::WRITABLE;
+        }
+        if !executable {
+            flags |= PageFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+fn check_segment(
+    elf: &Elf64,
+    ph: &Elf64Phdr,
+    load_bias: u64,
+    reserved: &Range<u64>,
+) -> Result<(), ElfError> {
+    let file_end = ph.p_offset.checked_add(ph.p_filesz);
+    if ph.p_filesz > ph.p_memsz || file_end.is_none_or(|end| end > elf.buf.len() as u64) {
+        return Err(ElfError::SegmentOutOfFile);
+    }
+
+    let mem_end = ph
+        .p_vaddr
+        .checked_add(load_bias)
+        .and_then(|vaddr| vaddr.checked_add(ph.p_memsz));
+    // a syscall in the last user page would return to a non-canonical address.
+    let Some(mem_end) = mem_end.filter(|&end| end <= USER_SPACE_TOP - FRAME_SIZE as u64) else {
+        return Err(ElfError::SegmentNotInUserSpace);
+    };
+
+    // segments are mapped a page at a time.
+    let start = page_align_down(ph.p_vaddr + load_bias);
+    let end = page_align_up(mem_end);
+    if start < reserved.end && reserved.start < end {
+        return Err(ElfError::SegmentOverlapsReserved);
+    }
+
+    Ok(())
+}
+
+fn check_dynamic(elf: &Elf64) -> Result<(), ElfError> {
+    for dynamic in elf.dynamic()? {
+        let d_tag = dynamic.d_tag;
+        match d_tag {
+            t if t == DynTag::DtNull as i64 => break,
+            t if t == DynTag::DtNeeded as i64 => return Err(ElfError::NeedsSharedLibrary),
+            // we only know how to process the RELA flavour of relocations.
+            t if t == DynTag::DtRel as i64 => return Err(ElfError::BadDynamicSection),
+            t if t == DynTag::DtRelaent as i64 => {
+                if dynamic.d_val != core::mem::size_of::<Elf64Rela>() as u64 {
+                    return Err(ElfError::BadDynamicSection);
+                }
+            }
+            _ => continue,
+        }
+    }
+    Ok(())
+}
+
+fn write_u64(page_table: &Frame, vaddr: u64, value: u64) -> Result<(), ElfError> {
+    // relocation targets need not be aligned, the value may straddle two pages.
+    let bytes = value.to_le_bytes();
+    let mut written = 0;
+    while written < bytes.len() {
+        let vaddr = vaddr + written as u64;
+        let phys = vmm::translate(page_table, vaddr).ok_or(ElfError::BadRelocation)?;
+        let offset = (phys % FRAME_SIZE as u64) as usize;
+        let len = (FRAME_SIZE - offset).min(bytes.len() - written);
+
+        let page: &mut [u8] =
+            Frame::from_u64(page_align_down(phys), FRAME_SIZE).to_higher_half_slice_mut();
+        page[offset..offset + len].copy_from_slice(&bytes[written..written + len]);
+        written += len;
+    }
+
+    Ok(())
+}
+
+/// Applies the R_X86_64_RELATIVE, R_X86_64_64 and R_X86_64_GLOB_DAT relocations of every
+/// allocated SHT_RELA section to the already mapped image.
+fn relocate(elf: &Elf64, page_table: &Frame, load_bias: u64) -> Result<(), ElfError> {
+    for sh in elf.sheaders {
+        if sh.sh_type != ShType::ShtRela as u32 || sh.sh_flags & ShFlags::ShfAlloc as u64 == 0 {
+            continue;
+        }
+
+        let relas: &[Elf64Rela] = elf.section_table(sh).ok_or(ElfError::BadRelocation)?;
+        let symbols: &[Elf64Sym] = match elf.sheaders.get(sh.sh_link as usize) {
+            Some(symtab) if sh.sh_link != 0 => {
+                elf.section_table(symtab).ok_or(ElfError::BadRelocation)?
+            }
+            _ => &[],
+        };
+
+        for rela in relas {
+            let r_type = (rela.r_info & 0xffffffff) as u32;
+            let r_sym = (rela.r_info >> 32) as u32;
+            // only ever write into the image itself, never into the rest of the address space.
+            let target = rela
+                .r_offset
+                .checked_add(load_bias)
+                .filter(|&target| elf.segment_containing(load_bias, target, 8).is_some())
+                .ok_or(ElfError::BadRelocation)?;
+            let addend = rela.r_addend;
+
+            let symbol_value = || -> Result<u64, ElfError> {
+                let sym = symbols
+                    .get(r_sym as usize)
+                    .ok_or(ElfError::UndefinedSymbol(r_sym))?;
+                if sym.st_shndx == SHN_UNDEF {
+                    return Err(ElfError::UndefinedSymbol(r_sym));
+                }
+                if sym.st_shndx == SHN_ABS {
+                    return Ok(sym.st_value);
+                }
+                sym.st_value
+                    .checked_add(load_bias)
+                    .ok_or(ElfError::BadRelocation)
+            };
+
+            let value = match r_type {
+                t if t == RelocType::RX86_64None as u32 => continue,
+                t if t == RelocType::RX86_64Relative as u32 => load_bias
+                    .checked_add_signed(addend)
+                    .ok_or(ElfError::BadRelocation)?,
+                t if t == RelocType::RX86_64_64 as u32 => symbol_value()?
+                    .checked_add_signed(addend)
+                    .ok_or(ElfError::BadRelocation)?,
+                t if t == RelocType::RX86_64GlobDat as u32 => symbol_value()?,
+                t => return Err(ElfError::UnsupportedRelocation(t)),
+            };
+
+            write_u64(page_table, target, value)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn map_image(
+    elf: &Elf64,
+    page_table: &Frame,
+    load_bias: u64,
+    pmm: &mut Pmm,
+) -> Result<(), ElfError> {
+    for ph in elf.load_segments() {
+        let vaddr = ph.p_vaddr + load_bias;
+        let file_start = vaddr;
+        let file_end = vaddr + ph.p_filesz;
+
+        let mut page = page_align_down(vaddr);
+        while page < vaddr + ph.p_memsz {
+            let phys = match vmm::translate(page_table, page) {
+                Some(phys) => phys,
+                None => {
+                    let frame = pmm.alloc_frame(1)?;
+                    let phys = frame.phy_ptr();
+                    frame.to_higher_half_slice_mut::<u8>().fill(0);
+
+                    let flags = elf.page_flags(page - load_bias);
+                    if let Err(e) = vmm::map(page_table, page, phys, flags, pmm) {
+                        pmm.dealloc_frame(Frame::from_u64(phys, FRAME_SIZE));
+                        return Err(e.into());
+                    }
+                    phys
+                }
+            };
+
+            // copy the part of the file image that falls into this page.
+            let copy_start = file_start.max(page);
+            let copy_end = file_end.min(page + FRAME_SIZE as u64);
+            if copy_start < copy_end {
+                let dest: &mut [u8] = Frame::from_u64(phys, FRAME_SIZE).to_higher_half_slice_mut();
+                let src_offset = (ph.p_offset + (copy_start - vaddr)) as usize;
+                let len = (copy_end - copy_start) as usize;
+                let dest_offset = (copy_start - page) as usize;
+
+                dest[dest_offset..dest_offset + len]
+                    .copy_from_slice(&elf.buf[src_offset..src_offset + len]);
+            }
+
+            page += FRAME_SIZE as u64;
+        }
+    }
+
+    let e_type = elf.header.e_type;
+    if e_type == ElfType::EtDyn as u16 {
+        relocate(elf, page_table, load_bias)?;
+    }
+
+    Ok(())
+}
+
+// The program headers are found through PT_PHDR if present, otherwise through the PT_LOAD
+// segment whose file contents cover them. 0 if they are not part of the loaded image.
+fn phdr_address(elf: &Elf64, load_bias: u64) -> Elf64Addr {
+    let size = size_of_val(elf.pheaders) as u64;
+
+    let address = match elf
+        .pheaders
+        .iter()
+        .find(|ph| ph.p_type == PhType::PtPhdr as u32)
+    {
+        Some(ph) => ph.p_vaddr.checked_add(load_bias),
+        None => {
+            let phoff = elf.header.e_phoff;
+            let phend = phoff.checked_add(size);
+            elf.load_segments()
+                .find(|ph| match (phend, ph.p_offset.checked_add(ph.p_filesz)) {
+                    (Some(phend), Some(file_end)) => ph.p_offset <= phoff && phend <= file_end,
+                    _ => false,
+                })
+                .and_then(|ph| ph.p_vaddr.checked_add(phoff - ph.p_offset))
+                .and_then(|vaddr| vaddr.checked_add(load_bias))
+        }
+    };
+
+    address
+        .filter(|&address| elf.segment_containing(load_bias, address, size).is_some())
+        .unwrap_or(0)
+}
+
+/// Creates a fresh user address space and maps every PT_LOAD segment of `elf` into it: file
+/// contents are copied in, the rest of the segment up to `p_memsz` is zero-filled.
+///
+/// ET_DYN (PIE) executables are placed at `load_bias` and relocated, ET_EXEC executables are
+/// always loaded at their link address and `load_bias` is ignored. No segment may overlap
+/// `reserved`, which the caller maps itself afterwards. On failure everything that was already
+/// mapped is given back to `pmm`.
+pub fn load(
+    elf: &Elf64,
+    load_bias: u64,
+    reserved: &Range<u64>,
+    pmm: &mut Pmm,
+) -> Result<LoadedElf, ElfError> {
+    let e_type = elf.header.e_type;
+    let load_bias = match e_type {
+        t if t == ElfType::EtExec as u16 => 0,
+        t if t == ElfType::EtDyn as u16 => load_bias,
+        t => return Err(ElfError::UnsupportedType(t)),
+    };
+
+    if load_bias % FRAME_SIZE as u64 != 0 {
+        return Err(ElfError::MisalignedLoadBias);
+    }
+
+    for ph in elf.load_segments() {
+        check_segment(elf, ph, load_bias, reserved)?;
+    }
+    check_dynamic(elf)?;
+
+    let entry = elf
+        .header
+        .e_entry
+        .checked_add(load_bias)
+        .filter(|&entry| {
+            elf.segment_containing(load_bias, entry, 1)
+                .is_some_and(|ph| ph.p_flags & Pflags::PfX as u32 != 0)
+        })
+        .ok_or(ElfError::EntryOutsideImage)?;
+
+    let page_table = vmm::new_address_space(pmm)?;
+    if let Err(e) = map_image(elf, &page_table, load_bias, pmm) {
+        vmm::destroy_address_space(page_table, pmm);
+        return Err(e);
+    }
+
+    Ok(LoadedElf {
+        entry,
+        load_bias,
+        page_table,
+        phdr: phdr_address(elf, load_bias),
+        phent: elf.header.e_phentsize as u64,
+        phnum: elf.header.e_phnum as u64,
+    })
 }
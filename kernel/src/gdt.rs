@@ -1,6 +1,9 @@
 use core::arch::asm;
 
 // use crate::bochs_breakpoint;
+use crate::kprintln;
+use crate::pmm::FRAME_SIZE;
+use crate::vmm;
 
 static NUM_GDT_ENTRIES: usize = 10;
 static NUM_TSS_ENTRIES: usize = 1;
@@ -81,13 +84,42 @@ struct Tss {
     i_o_map_base_address: u16,
 }
 
-static mut GDT_FULL: GdtFull = GdtFull {
-    gdt_entries: [GdtEntry::default(); NUM_GDT_ENTRIES],
-    tss_entries: [TssEntry::default(); NUM_TSS_ENTRIES],
-};
+/// The GDT, TSS and exception stacks of one CPU. Every CPU needs a TSS of its own, for its own
+/// stacks and because `ltr` marks the TSS descriptor as busy.
+#[repr(C)]
+pub struct CpuTables {
+    gdt: GdtFull,
+    gdt_pointer: GdtPointer,
+    tss: Tss,
+    double_fault_stack: GuardedStack,
+    nmi_stack: GuardedStack,
+    machine_check_stack: GuardedStack,
+}
 
-static mut GDT_POINTER: GdtPointer = GdtPointer { limit: 0, base: 0 };
-static mut TSS: Tss = unsafe { core::mem::zeroed() };
+impl CpuTables {
+    pub const fn new() -> CpuTables {
+        CpuTables {
+            gdt: GdtFull {
+                gdt_entries: [GdtEntry::default(); NUM_GDT_ENTRIES],
+                tss_entries: [TssEntry::default(); NUM_TSS_ENTRIES],
+            },
+            gdt_pointer: GdtPointer { limit: 0, base: 0 },
+            tss: unsafe { core::mem::zeroed() },
+            double_fault_stack: GuardedStack::new(),
+            nmi_stack: GuardedStack::new(),
+            machine_check_stack: GuardedStack::new(),
+        }
+    }
+
+    /// The stacks with a guard page, named as in the fault reports.
+    pub fn guarded_stacks(&self) -> [(&'static str, *const GuardedStack); 3] {
+        [
+            ("double fault", &raw const self.double_fault_stack),
+            ("NMI", &raw const self.nmi_stack),
+            ("machine check", &raw const self.machine_check_stack),
+        ]
+    }
+}
 
 impl GdtEntry {
     // this is done solely for the reason that #[deriving(Default)] is not a comp.
@@ -248,11 +280,16 @@ unsafe fn gdt_set_tss(
 
 
 
-pub unsafe fn init(kernel_stack_ptr: u64, interrupt_stack_ptr: u64) {
-    GDT_POINTER.base = (&raw const GDT_FULL) as u64;
-    GDT_POINTER.limit = (core::mem::size_of::<GdtFull>() - 1) as u16;
+/// Builds the GDT and TSS in `tables` and loads them on the calling CPU.
+pub unsafe fn init(tables: *mut CpuTables, kernel_stack_ptr: u64) {
+    let gdt = &raw mut (*tables).gdt;
+    let gdt_pointer = &raw mut (*tables).gdt_pointer;
+    let tss = &raw mut (*tables).tss;
+
+    (*gdt_pointer).base = gdt as u64;
+    (*gdt_pointer).limit = (core::mem::size_of::<GdtFull>() - 1) as u16;
 
-    assert_eq!(GDT_POINTER.base & 0x7, 0, "GDT must be 8-byte aligned");
+    assert_eq!((*gdt_pointer).base & 0x7, 0, "GDT must be 8-byte aligned");
 
     /*
     GDT_FULL.gdt_entries[0] = 0x0000000000000000;
@@ -268,7 +305,7 @@ pub unsafe fn init(kernel_stack_ptr: u64, interrupt_stack_ptr: u64) {
 
     // Kernel Code segment
     gdt_set_gate(
-        &raw mut GDT_FULL,
+        gdt,
         5,
         0,
         0,
@@ -281,7 +318,7 @@ pub unsafe fn init(kernel_stack_ptr: u64, interrupt_stack_ptr: u64) {
 
     // Kernel Data segment
     gdt_set_gate(
-        &raw mut GDT_FULL,
+        gdt,
         6,
         0,
         0,
@@ -292,25 +329,25 @@ pub unsafe fn init(kernel_stack_ptr: u64, interrupt_stack_ptr: u64) {
         GdtLimitGranuality::GRANULARITY_BYTE,
     );
 
-    // User Code segment
+    // User Data segment, sysret expects it right below user code
     gdt_set_gate(
-        &raw mut GDT_FULL,
+        gdt,
         7,
         0,
         0,
-        SegmentType::CODE_EXECUTE_READ,
+        SegmentType::DATA_READ_WRITE,
         DescriptorType::CODE_OR_DATA,
         GdtPrivilegeLevel::PL_3,
         GdtLimitGranuality::GRANULARITY_BYTE,
     );
 
-    // User Data segment
+    // User Code segment
     gdt_set_gate(
-        &raw mut GDT_FULL,
+        gdt,
         8,
         0,
         0,
-        SegmentType::DATA_READ_WRITE,
+        SegmentType::CODE_EXECUTE_READ,
         DescriptorType::CODE_OR_DATA,
         GdtPrivilegeLevel::PL_3,
         GdtLimitGranuality::GRANULARITY_BYTE,
@@ -318,26 +355,93 @@ pub unsafe fn init(kernel_stack_ptr: u64, interrupt_stack_ptr: u64) {
 
     // TSS segment
     gdt_set_tss(
-        &raw mut GDT_FULL,
+        gdt,
         0,
-        (&raw mut TSS) as u64,
+        tss as u64,
         0x67,
         SegmentType::TSS_64_BIT_AVAILABLE,
         GdtLimitGranuality::GRANULARITY_BYTE,
     );
 
-    TSS.rsp0 = kernel_stack_ptr;
-    TSS.ist1 = interrupt_stack_ptr;
+    (*tss).rsp0 = kernel_stack_ptr;
+    (*tss).ist1 = stack_top(&raw const (*tables).double_fault_stack);
+    (*tss).ist2 = stack_top(&raw const (*tables).nmi_stack);
+    (*tss).ist3 = stack_top(&raw const (*tables).machine_check_stack);
 
     asm!(
         "lgdt [{}]",
-        in(reg) &raw const GDT_POINTER,
+        in(reg) gdt_pointer,
         options(readonly, nostack, preserves_flags)
     );
 
-    TSS.rsp0 = kernel_stack_ptr as u64;
     asm!(
         "ltr {0:x}",
         in(reg) (NUM_GDT_ENTRIES * 8)
     );
 }
+
+/// Sets the stack the CPU owning `tables` switches to when an interrupt arrives while running
+/// in ring 3.
+pub fn set_kernel_stack(tables: &mut CpuTables, kernel_stack_ptr: u64) {
+    tables.tss.rsp0 = kernel_stack_ptr;
+}
+
+pub const STACK_SIZE: usize = 16384;
+
+/// A kernel stack with an extra page below it, which `guard_stacks` unmaps so that running off
+/// the end of the stack faults instead of silently overwriting whatever lies below.
+#[repr(C, align(4096))]
+pub struct GuardedStack {
+    guard: [u8; FRAME_SIZE],
+    stack: [u8; STACK_SIZE],
+}
+
+impl GuardedStack {
+    pub const fn new() -> GuardedStack {
+        GuardedStack {
+            guard: [0; FRAME_SIZE],
+            stack: [0; STACK_SIZE],
+        }
+    }
+}
+
+/// The Interrupt Stack Table slots of the exceptions that must not run on the current stack,
+/// as it may be the reason they happened.
+pub struct Ist {}
+
+impl Ist {
+    pub const DOUBLE_FAULT: u8 = 1;
+    pub const NMI: u8 = 2;
+    pub const MACHINE_CHECK: u8 = 3;
+}
+
+pub fn stack_top(stack: *const GuardedStack) -> u64 {
+    stack as u64 + core::mem::size_of::<GuardedStack>() as u64
+}
+
+pub fn guard_page(stack: *const GuardedStack) -> u64 {
+    stack as u64
+}
+
+/// Unmaps the guard page below `stack`, which needs the VMM. The kernel's page tables are shared
+/// by every address space, so this covers them all. Returns the frame that was mapped there.
+pub fn guard_stack(name: &str, stack: *const GuardedStack) -> Option<u64> {
+    let kernel_page_table = vmm::kernel_address_space();
+
+    match vmm::unmap(&kernel_page_table, guard_page(stack)) {
+        Ok(phys) => Some(phys),
+        Err(e) => {
+            kprintln!(
+                "could not unmap the guard page of the {} stack: {:?}",
+                name,
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Whether a fault at `addr` hit the guard page of `stack`, i.e. the stack overflowed.
+pub fn hits_guard_page(stack: *const GuardedStack, addr: u64) -> bool {
+    (guard_page(stack)..guard_page(stack) + FRAME_SIZE as u64).contains(&addr)
+}
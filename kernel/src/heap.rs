@@ -0,0 +1,211 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+
+use crate::kprintln;
+use crate::pmm::{FRAME_SIZE, PMM};
+use crate::sync::Spinlock;
+use crate::vmm::{self, PageFlags};
+
+// The heap lives in its own PML4 slot (index 384) so that it never collides with the HHDM or
+// the kernel image, and can grow up to 512GiB without needing another top level entry.
+const HEAP_START: u64 = 0xffff_c000_0000_0000;
+const HEAP_MAX_SIZE: u64 = 512 * 1024 * 1024 * 1024;
+const HEAP_INITIAL_SIZE: u64 = 64 * 1024;
+
+// every block handed out or kept in the free list is a multiple of this, which is also big
+// enough to hold a `FreeBlock` header.
+const BLOCK_ALIGN: usize = 16;
+
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+/// A first-fit free list allocator, sorted by address so neighbouring blocks can be merged.
+struct Heap {
+    head: *mut FreeBlock,
+    top: u64, // end of the mapped part of the heap
+}
+
+unsafe impl Send for Heap {}
+
+pub struct KernelAllocator {
+    heap: Spinlock<Heap>,
+}
+
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator {
+    heap: Spinlock::new(Heap {
+        head: ptr::null_mut(),
+        top: HEAP_START,
+    }),
+};
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+fn block_size(layout: &Layout) -> usize {
+    align_up(layout.size().max(BLOCK_ALIGN), BLOCK_ALIGN)
+}
+
+impl Heap {
+    /// Maps enough fresh pages at the top of the heap to hold at least `min_size` bytes and adds
+    /// them to the free list.
+    fn grow(&mut self, min_size: usize) -> bool {
+        let size = align_up(min_size, FRAME_SIZE) as u64;
+        if self.top + size > HEAP_START + HEAP_MAX_SIZE {
+            return false;
+        }
+
+        // cr3 may be a task's address space, which only shares the upper half's tables.
+        let kernel_page_table = vmm::kernel_address_space();
+        let mut pmm = PMM.lock();
+        let start = self.top;
+
+        while self.top < start + size {
+            let frame = match pmm.alloc_frame(1) {
+                Ok(frame) => frame,
+                Err(_) => break,
+            };
+
+            let flags = PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::NO_EXECUTE;
+            if vmm::map(
+                &kernel_page_table,
+                self.top,
+                frame.phy_ptr(),
+                flags,
+                &mut pmm,
+            )
+            .is_err()
+            {
+                pmm.dealloc_frame(frame);
+                break;
+            }
+            self.top += FRAME_SIZE as u64;
+        }
+        drop(pmm);
+
+        if self.top == start {
+            return false;
+        }
+
+        unsafe { self.free(start as *mut u8, (self.top - start) as usize) };
+        self.top - start >= size
+    }
+
+    unsafe fn alloc(&mut self, layout: &Layout) -> *mut u8 {
+        let size = block_size(layout);
+        let align = layout.align().max(BLOCK_ALIGN);
+
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut current = self.head;
+
+        while !current.is_null() {
+            let block_start = current as usize;
+            let block_end = block_start + (*current).size;
+            let start = align_up(block_start, align);
+
+            if start + size <= block_end {
+                let next = (*current).next;
+                let mut link = |block: *mut FreeBlock| {
+                    if prev.is_null() {
+                        self.head = block;
+                    } else {
+                        (*prev).next = block;
+                    }
+                    prev = block;
+                };
+
+                // both leftovers are multiples of BLOCK_ALIGN, so either empty or a valid block.
+                if start > block_start {
+                    (*current).size = start - block_start;
+                    link(current);
+                }
+                if block_end > start + size {
+                    let rest = (start + size) as *mut FreeBlock;
+                    (*rest).size = block_end - (start + size);
+                    link(rest);
+                }
+                link(next);
+
+                return start as *mut u8;
+            }
+
+            prev = current;
+            current = (*current).next;
+        }
+
+        ptr::null_mut()
+    }
+
+    unsafe fn free(&mut self, ptr: *mut u8, size: usize) {
+        let block = ptr as *mut FreeBlock;
+        (*block).size = size;
+
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut next = self.head;
+        while !next.is_null() && (next as usize) < (block as usize) {
+            prev = next;
+            next = (*next).next;
+        }
+
+        (*block).next = next;
+        if !next.is_null() && block as usize + (*block).size == next as usize {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+
+        if prev.is_null() {
+            self.head = block;
+        } else if prev as usize + (*prev).size == block as usize {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        } else {
+            (*prev).next = block;
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.heap.lock();
+
+        let ptr = heap.alloc(&layout);
+        if !ptr.is_null() {
+            return ptr;
+        }
+
+        // worst case the block has to be aligned inside the freshly mapped region.
+        if heap.grow(block_size(&layout) + layout.align()) {
+            return heap.alloc(&layout);
+        }
+        ptr::null_mut()
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.heap.lock().free(ptr, block_size(&layout));
+    }
+}
+
+/// Maps the initial heap. This has to happen before any address space is cloned from the
+/// kernel's, as it creates the PML4 entry that every address space then shares.
+pub fn init() {
+    let mut heap = ALLOCATOR.heap.lock();
+    assert!(
+        heap.grow(HEAP_INITIAL_SIZE as usize),
+        "could not map the kernel heap!"
+    );
+
+    kprintln!("heap: {:#x} - {:#x}", HEAP_START, heap.top);
+}
+
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    kprintln!(
+        "kernel heap allocation of {} bytes (align {}) failed!",
+        layout.size(),
+        layout.align()
+    );
+    crate::hcf();
+}
@@ -1,6 +1,10 @@
 use crate::{
+    apic,
+    gdt::Ist,
     kprint::{inb, io_wait, outb},
-    kprintln,
+    kprintln, kprintln_emergency, percpu, scheduler, smp,
+    sync::Spinlock,
+    uaccess,
 };
 use core::arch::{asm, global_asm};
 
@@ -32,81 +36,108 @@ static mut IDT: IdtArray = unsafe { core::mem::zeroed() };
 global_asm!(include_str!("idt.S"));
 
 extern "C" {
-    fn int_wrapper_0(r: *mut Regs);
-    fn int_wrapper_1(r: *mut Regs);
-    fn int_wrapper_2(r: *mut Regs);
-    fn int_wrapper_3(r: *mut Regs);
-    fn int_wrapper_4(r: *mut Regs);
-    fn int_wrapper_5(r: *mut Regs);
-    fn int_wrapper_6(r: *mut Regs);
-    fn int_wrapper_7(r: *mut Regs);
-    fn int_wrapper_8(r: *mut Regs);
-    fn int_wrapper_9(r: *mut Regs);
-    fn int_wrapper_10(r: *mut Regs);
-    fn int_wrapper_11(r: *mut Regs);
-    fn int_wrapper_12(r: *mut Regs);
-    fn int_wrapper_13(r: *mut Regs);
-    fn int_wrapper_14(r: *mut Regs);
-    fn int_wrapper_16(r: *mut Regs);
-    fn int_wrapper_17(r: *mut Regs);
-    fn int_wrapper_18(r: *mut Regs);
-    fn int_wrapper_19(r: *mut Regs);
-    fn int_wrapper_20(r: *mut Regs);
-    fn int_wrapper_21(r: *mut Regs);
-
-    fn int_wrapper_32(r: *mut Regs);
-    fn int_wrapper_33(r: *mut Regs);
+    // addresses of int_wrapper_0 to int_wrapper_255, see idt.S.
+    static int_wrapper_table: [u64; 256];
 }
 
-unsafe fn idt_set_handler(
-    ist: u8,
-    interrupt_vector: usize,
-    handler_fn: unsafe extern "C" fn(*mut Regs),
-    type_attribute: u8,
-) {
+unsafe fn idt_set_handler(ist: u8, interrupt_vector: usize, handler: u64, type_attribute: u8) {
     let entry = &mut IDT.entries[interrupt_vector];
 
-    entry.offset_1 = ((handler_fn as u64) & 0xffff) as u16;
+    entry.offset_1 = (handler & 0xffff) as u16;
     entry.selector = 0x28;
     entry.ist = ist;
     entry.type_attributes = type_attribute;
-    entry.offset_2 = (((handler_fn as u64) >> 16) & 0xffff) as u16;
-    entry.offset_3 = (((handler_fn as u64) >> 32) & 0xffffffff) as u32;
+    entry.offset_2 = ((handler >> 16) & 0xffff) as u16;
+    entry.offset_3 = ((handler >> 32) & 0xffffffff) as u32;
     entry.zero = 0;
 }
 
+pub type IrqHandler = fn(&mut Regs);
+
+#[allow(dead_code)] // no driver claims a line yet
+#[derive(Debug)]
+pub enum IrqError {
+    ReservedVector, // CPU exceptions and the timer are handled by the kernel itself
+    AlreadyClaimed,
+}
+
+struct IrqTable {
+    handlers: [Option<IrqHandler>; 256],
+    reported: [bool; 256], // unclaimed vectors we already complained about
+}
+
+static IRQ_TABLE: Spinlock<IrqTable> = Spinlock::new(IrqTable {
+    handlers: [None; 256],
+    reported: [false; 256],
+});
+
+/// Makes `handler` run whenever `vector` fires. The end of interrupt is sent after the handler
+/// returns.
+pub fn register_irq(vector: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    if vector < 32 || vector == TIMER_VECTOR {
+        return Err(IrqError::ReservedVector);
+    }
+
+    let mut table = IRQ_TABLE.lock();
+    let slot = &mut table.handlers[vector as usize];
+    if slot.is_some() {
+        return Err(IrqError::AlreadyClaimed);
+    }
+
+    *slot = Some(handler);
+    Ok(())
+}
+
+fn dispatch_irq(regs: &mut Regs) {
+    let vector = regs.interrupt_number as usize;
+
+    let (handler, report) = {
+        let mut table = IRQ_TABLE.lock();
+        let handler = table.handlers[vector];
+        let report = handler.is_none() && !table.reported[vector];
+        table.reported[vector] |= report;
+        (handler, report)
+    };
+
+    match handler {
+        Some(handler) => handler(regs),
+        None if report => kprintln!("unclaimed interrupt {}, ignoring it", vector),
+        None => {}
+    }
+
+    // every vector the local APIC delivers waits for its EOI except the spurious one, the 8259
+    // only knows its own 16 lines.
+    if apic::is_enabled() {
+        if vector != apic::SPURIOUS_VECTOR as usize {
+            apic::lapic_end_of_interrupt();
+        }
+    } else if (IRQ_BASE as usize..IRQ_BASE as usize + 16).contains(&vector) {
+        pic_send_end_of_interrupt((vector - IRQ_BASE as usize) as u8);
+    }
+}
+
 pub unsafe fn init() {
     // setup the interrupt descriptor table
     IDTR.limit = (core::mem::size_of::<IdtEntry>() * 256) as u16;
     IDTR.base = ((&IDT.entries[0]) as *const IdtEntry) as u64;
 
-    idt_set_handler(0, 0, int_wrapper_0, 0x8E);
-    idt_set_handler(0, 0x1, int_wrapper_1, 0x8E);
-    idt_set_handler(0, 0x2, int_wrapper_2, 0x8E);
-    idt_set_handler(0, 0x3, int_wrapper_3, 0x8E);
-    idt_set_handler(0, 0x4, int_wrapper_4, 0x8E);
-    idt_set_handler(0, 0x5, int_wrapper_5, 0x8E);
-    idt_set_handler(0, 0x6, int_wrapper_6, 0x8E);
-    idt_set_handler(0, 0x7, int_wrapper_7, 0x8E);
-    idt_set_handler(0, 0x8, int_wrapper_8, 0x8F); //
-    idt_set_handler(0, 0x9, int_wrapper_9, 0x8E);
-    idt_set_handler(0, 0xa, int_wrapper_10, 0x8F); //
-    idt_set_handler(0, 0xb, int_wrapper_11, 0x8F); //
-    idt_set_handler(0, 0xc, int_wrapper_12, 0x8F); //
-    idt_set_handler(0, 0xd, int_wrapper_13, 0x8F); //
-    idt_set_handler(0, 0xe, int_wrapper_14, 0x8F); //
-    idt_set_handler(0, 0xf, int_wrapper_16, 0x8E);
-    idt_set_handler(0, 0x10, int_wrapper_17, 0x8F); //
-    idt_set_handler(0, 0x11, int_wrapper_18, 0x8E);
-    idt_set_handler(0, 0x12, int_wrapper_19, 0x8E);
-    idt_set_handler(0, 0x13, int_wrapper_20, 0x8E);
-    idt_set_handler(0, 0x14, int_wrapper_21, 0x8E);
+    for (vector, &wrapper) in int_wrapper_table.iter().enumerate() {
+        // interrupt gates only: with IF still set, an IRQ could arrive before the wrapper of a
+        // ring 3 fault ran swapgs, and its own wrapper would keep the user's gs base.
+        let type_attribute = 0x8E;
+
+        // Only exceptions that may be caused by a broken stack get one of their own. Everything
+        // else, the timer in particular, has to push its frame onto the interrupted stack.
+        let ist = match vector {
+            0x2 => Ist::NMI,
+            0x8 => Ist::DOUBLE_FAULT,
+            0x12 => Ist::MACHINE_CHECK,
+            _ => 0,
+        };
+        idt_set_handler(ist, vector, wrapper, type_attribute);
+    }
 
-    asm!(
-        "lidt [{}]",
-        in(reg) &raw const IDTR,
-        options(readonly, nostack, preserves_flags)
-    );
+    load();
 
     init_pic();
     asm!("sti"); // set the interrupt flag
@@ -121,9 +152,18 @@ pub unsafe fn init() {
     }
 }
 
+/// Loads the IDT on the calling CPU, every CPU shares the one `init` built.
+pub unsafe fn load() {
+    asm!(
+        "lidt [{}]",
+        in(reg) &raw const IDTR,
+        options(readonly, nostack, preserves_flags)
+    );
+}
+
 #[derive(Debug)]
 #[repr(C, packed)]
-struct Regs {
+pub struct Regs {
     rax: u64,
     rbx: u64,
     rcx: u64,
@@ -131,7 +171,6 @@ struct Regs {
     rbp: u64,
     rsi: u64,
     rdi: u64,
-    rsp: u64,
     r8: u64,
     r9: u64,
     r10: u64,
@@ -141,87 +180,171 @@ struct Regs {
     r14: u64,
     r15: u64,
 
-    rflags: u64,
     interrupt_number: u64,
-    error_code: u64,
+    error_code: u64, // 0 for vectors without one
+
+    // pushed by the CPU, restored by iretq
     rip: u64,
+    cs: u64,
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
+}
+
+impl Regs {
+    /// Whether the interrupted code was running in ring 3.
+    fn is_user_mode(&self) -> bool {
+        self.cs & 3 == 3
+    }
 }
 
-fn timer(_r: &mut Regs) {
+fn timer(r: *mut Regs) -> *mut Regs {
     // kprintln!("Inside timer");
-    pic_send_end_of_interrupt();
+    end_of_interrupt(TIMER_VECTOR as usize);
+    if !percpu::preemptible() {
+        return r;
+    }
+    scheduler::schedule(r)
 }
 
+// Returns the register frame the interrupt wrapper should restore, which is `r` unless the
+// scheduler decided to switch to another task.
 #[no_mangle]
-unsafe extern "C" fn all_interrupts_handler(r: *mut Regs) {
+unsafe extern "C" fn all_interrupts_handler(r: *mut Regs) -> *mut Regs {
     let regs: &mut Regs = &mut *r;
-    match regs.interrupt_number {
+    let interrupt_number = regs.interrupt_number;
+    match interrupt_number {
+        2 => {
+            let rip = regs.rip;
+            kprintln_emergency!("NMI received at rip {:#x}, ignoring it", rip);
+        }
+        8 => {
+            let mut cr2: u64;
+            asm!("mov {0}, cr2", out(reg) cr2);
+
+            // the faulting access itself or the push that could not find room on the stack.
+            let rsp = regs.rsp;
+            match smp::overflowed_stack(cr2).or(smp::overflowed_stack(rsp)) {
+                Some((stack, cpu)) => panic!(
+                    "double fault: the {} stack of CPU {} overflowed, rsp {:#x}, cr2 {:#x}!\n{:#x?}",
+                    stack, cpu, rsp, cr2, regs
+                ),
+                None if scheduler::current_task_overflowed(cr2)
+                    || scheduler::current_task_overflowed(rsp) =>
+                {
+                    panic!(
+                        "double fault: the kernel stack of task {} overflowed, rsp {:#x}, cr2 {:#x}!\n{:#x?}",
+                        scheduler::current_task_id(),
+                        rsp,
+                        cr2,
+                        regs
+                    )
+                }
+                None => panic!(
+                    "double fault at rsp {:#x}, cr2 {:#x}!\n{:#x?}",
+                    rsp, cr2, regs
+                ),
+            }
+        }
+        18 => {
+            let rip = regs.rip;
+            panic!("machine check at rip {:#x}!\n{:#x?}", rip, regs);
+        }
         13 => {
-            kprintln!("We got a General Protection Fault!");
-            kprintln!("{:?}", regs);
-            loop {}
+            let (rip, error_code) = (regs.rip, regs.error_code);
+            if regs.is_user_mode() {
+                kprintln!(
+                    "task {} killed by SIGSEGV: general protection fault at rip {:#x} (error code {:#x})",
+                    scheduler::current_task_id(),
+                    rip,
+                    error_code
+                );
+                return scheduler::kill_current(r);
+            }
+
+            panic!(
+                "general protection fault in the kernel at rip {:#x}!\n{:#x?}",
+                rip, regs
+            );
         }
         14 => {
-            kprintln!("We got a page fault!");
+            // a bad pointer handed to copy_from_user/copy_to_user, not a kernel bug.
+            if let Some(fixup) = uaccess::fixup(regs.rip) {
+                regs.rip = fixup;
+                return r;
+            }
+
             let mut cr2: u64;
             asm!(
                 "mov {0}, cr2",
                 out(reg) cr2
             );
-            kprintln!("Page fault address: {:x}", cr2);
-            kprintln!("{:#x?}", regs);
-            loop {}
+
+            let (rip, error_code) = (regs.rip, regs.error_code);
+            if regs.is_user_mode() {
+                kprintln!(
+                    "task {} killed by SIGSEGV: page fault at {:#x}, rip {:#x} (error code {:#x})",
+                    scheduler::current_task_id(),
+                    cr2,
+                    rip,
+                    error_code
+                );
+                return scheduler::kill_current(r);
+            }
+
+            panic!(
+                "page fault in the kernel at {:#x}, rip {:#x}!\n{:#x?}",
+                cr2, rip, regs
+            );
         }
         32 => {
-            timer(regs);
-            // break;
+            return timer(r);
         }
-        33 => {
-            //TODO: keyboard
-            // break;
-        }
-        99 => {
-            match regs.rax {
-                0 => {
-                    // cleanup();
-                    // scheduler_cleanup_task();
-                }
-                1 => {
-                    // output_to_console((char*)r->rdi, r->rsi);
-                }
-                _ => {
-                    kprintln!("syscall number not recognized!");
-                    loop {}
-                }
+        vector if vector < 32 => {
+            let rip = regs.rip;
+            if regs.is_user_mode() {
+                kprintln!(
+                    "task {} killed by exception {} at rip {:#x}",
+                    scheduler::current_task_id(),
+                    vector,
+                    rip
+                );
+                return scheduler::kill_current(r);
             }
+
+            panic!(
+                "exception {} in the kernel at rip {:#x}!\n{:#x?}",
+                vector, rip, regs
+            );
         }
-        _ => {
-            let int_number = regs.interrupt_number;
-            kprintln!("we received an generic interrupt {}\n", int_number);
-            kprintln!("{:?}", regs);
-            loop {}
-        }
+        _ => dispatch_irq(regs),
     };
+
+    r
 }
 
 pub unsafe fn init_pic() {
     // we need to offset the pic interrupts, as they will overlap over the
     // software interrupts we defined above.
-    pic_remap(0x20, 0x28);
+    pic_remap(PIC_MASTER_OFFSET, PIC_SLAVE_OFFSET);
 
-    // setup hardware interrupt handlers
-    idt_set_handler(1, 0x20, int_wrapper_32, 0x8E);
-    idt_set_handler(0, 0x21, int_wrapper_33, 0x8E);
-
-    for vector in 0x22..0x30 {
-        idt_set_handler(0, vector, all_interrupts_handler, 0x8E);
-    }
+    pit_set_frequency(TIMER_HZ);
 
     // disable/mask all the hardware interrupts right now.
     // until we implement keyboard drivers.
     // pic_disable_all_interrupts(); // here masking means disabling
 }
 
+// where the PIC's IRQs 0-7 and 8-15 end up in the IDT, right after the CPU exceptions.
+const PIC_MASTER_OFFSET: i8 = 0x20;
+const PIC_SLAVE_OFFSET: i8 = 0x28;
+
+// the ISA IRQs 0-15 use the same vectors whether they come from the 8259 or the I/O APIC.
+pub const IRQ_BASE: u8 = PIC_MASTER_OFFSET as u8;
+
+// IRQ0, the PIT, on the BSP and the local APIC timer on the APs.
+pub const TIMER_VECTOR: u8 = IRQ_BASE;
+
 const PIC_MASTER_COMMAND: i16 = 0x0020;
 const PIC_MASTER_DATA: i16 = 0x0021;
 const PIC_SLAVE_COMMAND: i16 = 0x00A0;
@@ -301,14 +424,46 @@ unsafe fn pic_remap(master_offset: i8, slave_offset: i8) {
     outb(PIC_MASTER_DATA, inb(PIC_MASTER_DATA) & !(1 << 1)); // Unmask IRQ 1 (Keyboard)
 }
 
+/// Masks every line of the 8259, once the APICs took over. It stays remapped so that a spurious
+/// interrupt it still raises does not look like a CPU exception.
+pub fn disable_pic() {
+    pic_disable_all_interrupts();
+}
+
 // 'masking' here means disabling by setting the bit to be 1.
-#[allow(dead_code)]
 fn pic_disable_all_interrupts() {
     outb(PIC_MASTER_DATA, 0xff as u8 as i8);
     outb(PIC_SLAVE_DATA, 0xff as u8 as i8);
 }
 
-fn pic_send_end_of_interrupt() {
-    // set bit 5 of OCW 2
+fn end_of_interrupt(vector: usize) {
+    if apic::is_enabled() {
+        apic::lapic_end_of_interrupt();
+    } else {
+        pic_send_end_of_interrupt((vector - IRQ_BASE as usize) as u8);
+    }
+}
+
+fn pic_send_end_of_interrupt(irq: u8) {
+    // set bit 5 of OCW 2, the slave's lines also go through the master.
+    if irq >= 8 {
+        outb(PIC_SLAVE_COMMAND, 1 << 5);
+    }
     outb(PIC_MASTER_COMMAND, 1 << 5);
 }
+
+const PIT_CHANNEL_0_DATA: i16 = 0x0040;
+const PIT_COMMAND: i16 = 0x0043;
+pub const PIT_BASE_FREQUENCY: u32 = 1193182;
+
+// how often IRQ0 fires, and with it how often the scheduler gets to preempt a task.
+pub const TIMER_HZ: u32 = 100;
+
+fn pit_set_frequency(hz: u32) {
+    let divisor = PIT_BASE_FREQUENCY / hz;
+
+    // channel 0, lobyte/hibyte access, mode 3 (square wave generator)
+    outb(PIT_COMMAND, 0x36);
+    outb(PIT_CHANNEL_0_DATA, (divisor & 0xff) as u8 as i8);
+    outb(PIT_CHANNEL_0_DATA, ((divisor >> 8) & 0xff) as u8 as i8);
+}
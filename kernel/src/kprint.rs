@@ -1,10 +1,22 @@
 use core::arch::asm;
 use core::fmt;
 
+use crate::idt::{self, IrqError, Regs};
+use crate::sync::Spinlock;
+
 static COM1_PORT: i16 = 0x3f8;
 
+// the 16550's registers next to the data port, and the ISA IRQ it raises.
+const COM1_INTERRUPT_ENABLE: i16 = 0x3f9;
+const COM1_MODEM_CONTROL: i16 = 0x3fc;
+const COM1_LINE_STATUS: i16 = 0x3fd;
+const COM1_IRQ: u8 = 4;
+
 struct SerialWriter;
 
+// held for a whole message, so that the CPUs do not interleave their output.
+static CONSOLE: Spinlock<SerialWriter> = Spinlock::new(SerialWriter);
+
 // we want:  out    dx,al
 pub fn outb(port: i16, val: i8) {
     unsafe {
@@ -49,10 +61,62 @@ impl fmt::Write for SerialWriter {
     }
 }
 
+/// Writes raw bytes, which do not have to be valid UTF-8, to the console.
+pub fn kprint_bytes(bytes: &[u8]) {
+    let _console = CONSOLE.lock();
+    for &byte in bytes {
+        outb(COM1_PORT, byte as i8);
+    }
+}
+
+// gets every byte typed on the console once `enable_serial_input` ran.
+static SERIAL_INPUT: Spinlock<Option<fn(u8)>> = Spinlock::new(None);
+
+/// Makes COM1 raise its IRQ for received bytes and hands each of them to `handler`.
+pub fn enable_serial_input(handler: fn(u8)) -> Result<(), IrqError> {
+    *SERIAL_INPUT.lock() = Some(handler);
+    idt::register_irq(idt::IRQ_BASE + COM1_IRQ, serial_interrupt)?;
+
+    // OUT2 gates the UART's interrupt line on PCs, DTR and RTS stay set.
+    outb(COM1_MODEM_CONTROL, 0x0b);
+    outb(COM1_INTERRUPT_ENABLE, 0x01);
+    Ok(())
+}
+
+fn serial_interrupt(_regs: &mut Regs) {
+    let handler = *SERIAL_INPUT.lock();
+
+    // drain the receive buffer, the UART only raises its IRQ again for new data.
+    while unsafe { inb(COM1_LINE_STATUS) } & 0x01 != 0 {
+        let byte = unsafe { inb(COM1_PORT) } as u8;
+        if let Some(handler) = handler {
+            handler(byte);
+        }
+    }
+}
+
 pub fn kprint_internal(args: fmt::Arguments) {
-    let mut writer = SerialWriter;
+    let mut writer = CONSOLE.lock();
 
-    fmt::write(&mut writer, args).unwrap();
+    fmt::write(&mut *writer, args).unwrap();
+}
+
+// how often `kprint_emergency` tries to get the console before it writes without it.
+const EMERGENCY_TRIES: usize = 100_000;
+
+/// Prints from panics and NMIs, which may have interrupted a print on the same CPU: waits a
+/// moment for other CPUs to finish theirs, then writes without the console lock instead of
+/// spinning forever.
+pub fn kprint_emergency(args: fmt::Arguments) {
+    for _ in 0..EMERGENCY_TRIES {
+        if let Some(mut writer) = CONSOLE.try_lock() {
+            let _ = fmt::write(&mut *writer, args);
+            return;
+        }
+        core::hint::spin_loop();
+    }
+
+    let _ = fmt::write(&mut SerialWriter, args);
 }
 
 #[macro_export]
@@ -62,3 +126,11 @@ macro_rules! kprintln {
         kprint_internal(format_args_nl!($($arg)*));
     }};
 }
+
+/// `kprintln!` for panics and NMIs, see `kprint_emergency`.
+#[macro_export]
+macro_rules! kprintln_emergency {
+    ($($arg:tt)*) => {{
+        $crate::kprint::kprint_emergency(format_args_nl!($($arg)*));
+    }};
+}
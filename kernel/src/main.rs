@@ -1,29 +1,31 @@
 #![no_std]
 #![no_main]
 #![feature(format_args_nl)]
+#![feature(alloc_error_handler)]
+
+extern crate alloc;
 
 use core::arch::asm;
 
+mod acpi;
+mod apic;
 mod asa_limine;
+mod boot;
 mod cpu;
 mod elf;
 mod gdt;
+mod heap;
 mod idt;
 mod kprint;
+mod percpu;
 mod pmm;
+mod scheduler;
+mod smp;
+mod sync;
 mod syscall;
 mod task;
-
-static mut KERNEL_STACK_BASE: [u8; 16384] = unsafe { core::mem::zeroed() };
-static mut INTERRUPT_STACK_BASE: [u8; 16384] = unsafe { core::mem::zeroed() };
-
-#[allow(dead_code)]
-struct ProcessorContext {
-    user_stack_ptr: u64,
-    kernel_stack_ptr: u64,
-}
-
-static mut PROCESSOR_CONTEXT: ProcessorContext = unsafe { core::mem::zeroed() };
+mod uaccess;
+mod vmm;
 
 #[no_mangle]
 extern "C" fn kmain() -> ! {
@@ -32,37 +34,29 @@ extern "C" fn kmain() -> ! {
     assert!(asa_limine::BASE_REVISION.is_supported());
 
     unsafe {
-        let kernel_stack_ptr = KERNEL_STACK_BASE.as_ptr().byte_add(KERNEL_STACK_BASE.len()) as u64;
-        let interrupt_stack_ptr = INTERRUPT_STACK_BASE
-            .as_ptr()
-            .byte_add(INTERRUPT_STACK_BASE.len()) as u64;
-
-        asm!("mov rsp, {}", in(reg) kernel_stack_ptr);
-        gdt::init(kernel_stack_ptr as u64, interrupt_stack_ptr as u64);
+        asm!("mov rsp, {}", in(reg) smp::bsp_kernel_stack_top());
+        smp::init_bsp();
         idt::init();
     }
 
-    let mut allocator = pmm::init(&asa_limine::MEMMAP_REQUEST, &asa_limine::HHDM_REQUEST);
+    // the bootloader's responses may only be read until `boot::reclaim`, copy what we need.
+    boot::init();
+    pmm::init(boot::info().memmap(), boot::hhdm_offset());
+    vmm::init();
+    smp::guard_bsp_stacks();
+    heap::init();
+    acpi::init();
+
+    // from here on IRQs arrive through the APICs if there are any, with the 8259 masked.
+    unsafe { asm!("cli") };
+    if apic::init(idt::IRQ_BASE, acpi::tables().and_then(|t| t.madt.as_ref())) {
+        idt::disable_pic();
+    }
+    unsafe { asm!("sti") };
 
-    unsafe {
-        // we are enabling fast syscall in the processor.
-        cpu::wrmsr(
-            cpu::Msr::IA32_EFER,
-            cpu::rdmsr(cpu::Msr::IA32_EFER) | ((1 as u64) << 0),
-        );
-        cpu::wrmsr(cpu::Msr::IA32_FSTAR, 0x43700); // Clear IF,TF,AC, and DF
-
-        // this is syscall entry function
-        cpu::wrmsr(cpu::Msr::IA32_LSTAR, syscall::handler_fn as u64);
-        cpu::wrmsr(cpu::Msr::IA32_STAR, 0x0030002800000000);
-        cpu::wrmsr(
-            cpu::Msr::IA32_KERNEL_GS_BASE,
-            &raw const PROCESSOR_CONTEXT as *const ProcessorContext as u64,
-        );
-        cpu::wrmsr(
-            cpu::Msr::IA32_USER_GS_BASE,
-            &raw const PROCESSOR_CONTEXT as *const ProcessorContext as u64,
-        );
+    syscall::init();
+    if let Err(e) = kprint::enable_serial_input(debug_key) {
+        kprintln!("no debug keys on the console: {:?}", e);
     }
 
     // let current_page_table_address: &u64 = cpu::cr3().to_higher_half_ptr();
@@ -71,47 +65,70 @@ extern "C" fn kmain() -> ! {
     //     current_page_table_address
     // );
 
-    let modules = asa_limine::MODULE_REQUEST.get_response().unwrap().modules();
-
-    let program_elf = elf::parse(modules[0].addr(), modules[0].size());
-    kprintln!("{:#x?}", program_elf);
-
-    let task = task::Task::new(&mut allocator);
-
-    /*
-    scheduler_init();
+    let modules = boot::info().modules();
+
+    match elf::parse(modules[0].addr, modules[0].size) {
+        Ok(program_elf) => {
+            kprintln!("{:#x?}", program_elf);
+            for section in program_elf.sections() {
+                kprintln!(
+                    "section: [{}] type: {:#x} flags: {:#x} virtual: {:#x} offset: {:#x}",
+                    section.name,
+                    section.sh_type,
+                    section.flags,
+                    section.addr,
+                    section.offset
+                );
+            }
 
-    for (int i = 0; i < 100; i++) {
-        u64 argc = 3;
-        char* argv[] = {"hello-world", "hello darkness", "1000"};
-        Task task = task_init(&pmm_allocator, (PageTableEntry*) current_page_table_address, program_elf, argc, argv);
-        scheduler_queue_task(task);
+            let args = task::Args {
+                argv: &["hello-world", "hello darkness", "1000"],
+                envp: &[],
+            };
+
+            for _ in 0..100 {
+                match task::Task::new(&program_elf, &args) {
+                    Ok(task) => scheduler::queue_task(task),
+                    Err(e) => {
+                        kprintln!("could not load module: {:?}", e);
+                        break;
+                    }
+                }
+            }
+        }
+        Err(e) => kprintln!("module is not a valid ELF file: {:?}", e),
     }
 
-    scheduler_idle_loop();
-    */
-
-    if let Some(framebuffer_response) = asa_limine::FRAMEBUFFER_REQUEST.get_response() {
-        if let Some(framebuffer) = framebuffer_response.framebuffers().next() {
-            for i in 0..100_u64 {
-                // Calculate the pixel offset using the framebuffer information we obtained above.
-                // We skip `i` scanlines (pitch is provided in bytes) and add `i * 4` to skip `i` pixels forward.
-                let pixel_offset = i * framebuffer.pitch() + i * 4;
+    if let Some(framebuffer) = &boot::info().framebuffer {
+        for i in 0..100_u64 {
+            // Calculate the pixel offset using the framebuffer information we obtained above.
+            // We skip `i` scanlines (pitch is provided in bytes) and add `i * 4` to skip `i` pixels forward.
+            let pixel_offset = i * framebuffer.pitch + i * 4;
 
-                // Write 0xFFFFFFFF to the provided pixel offset to fill it white.
-                unsafe {
-                    *(framebuffer.addr().add(pixel_offset as usize) as *mut u32) = 0xFFFFFFFF;
-                }
+            // Write 0xFFFFFFFF to the provided pixel offset to fill it white.
+            unsafe {
+                *(framebuffer.addr.add(pixel_offset as usize) as *mut u32) = 0xFFFFFFFF;
             }
         }
     }
 
-    hcf();
+    smp::start_aps();
+    // the APs left Limine's stacks and page tables behind, nothing needs the boot data anymore.
+    boot::reclaim();
+    pmm::print_meminfo();
+    scheduler::idle_loop();
+}
+
+// single key commands typed on the serial console.
+fn debug_key(key: u8) {
+    if key == b'm' {
+        pmm::print_meminfo();
+    }
 }
 
 #[panic_handler]
 fn rust_panic(info: &core::panic::PanicInfo) -> ! {
-    kprintln!("PANIC! {}", info);
+    kprintln_emergency!("PANIC! {}", info);
     hcf();
 }
 
@@ -0,0 +1,111 @@
+use core::arch::asm;
+use core::mem::offset_of;
+
+use crate::cpu;
+
+/// The state of a CPU that code running on it reaches through `gs`, with `percpu_read!` and
+/// `percpu_write!`. While in the kernel the gs base points here, while in ring 3 it holds the
+/// task's own (always 0 for now), the entry and exit paths swap them with `swapgs`.
+#[repr(C)]
+pub struct PerCpu {
+    pub user_stack_ptr: u64,   // syscall_entry's scratch slot for the user rsp
+    pub kernel_stack_ptr: u64, // where syscall_entry switches to
+    pub cpu_id: u64,
+    pub current_task: u64,  // *const Task, 0 while the CPU idles
+    pub preempt_count: u64, // the timer only switches tasks while this is 0
+    pub run_queue: u64,     // *const Spinlock<RunQueue> the CPU takes its tasks from
+    pub scheduling: u64,    // 1 once the CPU handed itself over to its tasks in `idle_loop`
+}
+
+// syscall_entry uses these offsets directly, keep them in sync with syscall.S.
+const _: () = assert!(offset_of!(PerCpu, user_stack_ptr) == 0);
+const _: () = assert!(offset_of!(PerCpu, kernel_stack_ptr) == 8);
+
+impl PerCpu {
+    pub const fn new() -> PerCpu {
+        PerCpu {
+            user_stack_ptr: 0,
+            kernel_stack_ptr: 0,
+            cpu_id: 0,
+            current_task: 0,
+            preempt_count: 0,
+            run_queue: 0,
+            scheduling: 0,
+        }
+    }
+}
+
+/// Reads a field of the calling CPU's `PerCpu`. It is a single instruction, so the task cannot
+/// be moved to another CPU halfway through.
+#[macro_export]
+macro_rules! percpu_read {
+    ($field:ident) => {{
+        let value: u64;
+        unsafe {
+            core::arch::asm!(
+                "mov {}, gs:[{}]",
+                out(reg) value,
+                const core::mem::offset_of!($crate::percpu::PerCpu, $field),
+                options(nostack, readonly, preserves_flags)
+            );
+        }
+        value
+    }};
+}
+
+/// Writes a field of the calling CPU's `PerCpu`, in a single instruction like `percpu_read!`.
+#[macro_export]
+macro_rules! percpu_write {
+    ($field:ident, $value:expr) => {{
+        let value: u64 = $value;
+        unsafe {
+            core::arch::asm!(
+                "mov gs:[{}], {}",
+                const core::mem::offset_of!($crate::percpu::PerCpu, $field),
+                in(reg) value,
+                options(nostack, preserves_flags)
+            );
+        }
+    }};
+}
+
+/// Points the calling CPU's gs base at `percpu`, with a null user gs base for `swapgs` to
+/// switch to on the way out to ring 3.
+pub unsafe fn init(percpu: *mut PerCpu) {
+    cpu::wrmsr(cpu::Msr::IA32_GS_BASE, percpu as u64);
+    cpu::wrmsr(cpu::Msr::IA32_KERNEL_GS_BASE, 0);
+}
+
+/// Index of the calling CPU, 0 for the BSP.
+pub fn cpu_id() -> usize {
+    percpu_read!(cpu_id) as usize
+}
+
+/// Keeps the timer from switching away from the calling task until the matching
+/// `preempt_enable`. The calls nest.
+#[allow(dead_code)]
+pub fn preempt_disable() {
+    unsafe {
+        asm!(
+            "inc qword ptr gs:[{}]",
+            const offset_of!(PerCpu, preempt_count),
+            options(nostack)
+        );
+    }
+}
+
+#[allow(dead_code)]
+pub fn preempt_enable() {
+    unsafe {
+        asm!(
+            "dec qword ptr gs:[{}]",
+            const offset_of!(PerCpu, preempt_count),
+            options(nostack)
+        );
+    }
+}
+
+/// Whether the timer may switch away from the calling task.
+pub fn preemptible() -> bool {
+    percpu_read!(preempt_count) == 0
+}
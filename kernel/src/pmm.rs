@@ -1,7 +1,10 @@
-use crate::asa_limine;
+use crate::boot::{self, MemoryRegion};
 use crate::kprintln;
+use crate::sync::Spinlock;
+use core::mem::size_of;
+use core::ptr;
 use core::slice;
-use limine;
+use limine::memory_map::EntryType;
 // use core::error::Error;
 
 pub const FRAME_SIZE: usize = 4096;
@@ -18,8 +21,16 @@ impl Frame {
         return Frame { phy_ptr: ptr, size };
     }
 
+    pub fn phy_ptr(&self) -> u64 {
+        self.phy_ptr
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
     pub fn to_higher_half_ptr<'a, T>(self) -> &'a T {
-        let hddm = asa_limine::HHDM_REQUEST.get_response().unwrap().offset();
+        let hddm = boot::hhdm_offset();
 
         let virt_ptr: *const T = (self.phy_ptr + hddm) as *const T;
 
@@ -27,22 +38,194 @@ impl Frame {
     }
 
     pub fn to_higher_half_slice<'a, T>(self) -> &'a [T] {
-        let hddm = asa_limine::HHDM_REQUEST.get_response().unwrap().offset();
+        let hddm = boot::hhdm_offset();
         let virt_ptr: *const T = (self.phy_ptr + hddm) as *const T;
         unsafe { slice::from_raw_parts(virt_ptr, self.size / core::mem::size_of::<T>()) }
     }
 
     pub fn to_higher_half_slice_mut<'a, T>(self) -> &'a mut [T] {
-        let hddm = asa_limine::HHDM_REQUEST.get_response().unwrap().offset();
+        let hddm = boot::hhdm_offset();
         let virt_ptr: *mut T = (self.phy_ptr + hddm) as *mut T;
         unsafe { slice::from_raw_parts_mut(virt_ptr, self.size / core::mem::size_of::<T>()) }
     }
 }
 
+/// The ranges of physical memory that devices which cannot address all of it are limited to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    Dma,    // below 16MiB, for ISA DMA
+    Dma32,  // below 4GiB, for devices with 32 bit addressing
+    Normal, // everything else
+}
+
+const ZONES: usize = 3;
+
+impl Zone {
+    pub const ALL: [Zone; ZONES] = [Zone::Dma, Zone::Dma32, Zone::Normal];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Zone::Dma => "DMA",
+            Zone::Dma32 => "DMA32",
+            Zone::Normal => "Normal",
+        }
+    }
+
+    /// First physical address past the zone.
+    pub fn end(self) -> u64 {
+        match self {
+            Zone::Dma => 16 * 1024 * 1024,
+            Zone::Dma32 => 4 * 1024 * 1024 * 1024,
+            Zone::Normal => u64::MAX,
+        }
+    }
+
+    /// First physical address in the zone.
+    pub fn start(self) -> u64 {
+        match self {
+            Zone::Dma => 0,
+            Zone::Dma32 => Zone::Dma.end(),
+            Zone::Normal => Zone::Dma32.end(),
+        }
+    }
+
+    pub fn of(phys: u64) -> Zone {
+        Zone::ALL
+            .into_iter()
+            .find(|zone| phys < zone.end())
+            .unwrap()
+    }
+}
+
+// The buddy allocator hands out blocks of 2^order frames, up to 4MiB. Zone boundaries are
+// multiples of that, so no block ever straddles two zones.
+pub const MAX_ORDER: usize = 10;
+
+// marks a frame that does not start a free block in `Pmm::orders`.
+const NOT_A_FREE_BLOCK: u8 = u8::MAX;
+
+/// Frame counts of a zone or of a memory map type. `managed` frames are the ones handed to
+/// the allocators, the others are reserved: firmware, MMIO, the kernel image, the PMM's own
+/// bitmap, boot data that was not reclaimed yet.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameStats {
+    pub total: usize,
+    pub managed: usize,
+    pub free: usize,
+}
+
+impl FrameStats {
+    pub fn used(&self) -> usize {
+        self.managed - self.free
+    }
+
+    pub fn reserved(&self) -> usize {
+        self.total - self.managed
+    }
+
+    fn add(&mut self, other: &FrameStats) {
+        self.total += other.total;
+        self.managed += other.managed;
+        self.free += other.free;
+    }
+}
+
+// the memory map types Limine knows about, and one for any it might add.
+pub const MEMMAP_TYPES: usize = 9;
+
+fn type_index(kind: EntryType) -> usize {
+    match kind {
+        EntryType::USABLE => 0,
+        EntryType::RESERVED => 1,
+        EntryType::ACPI_RECLAIMABLE => 2,
+        EntryType::ACPI_NVS => 3,
+        EntryType::BAD_MEMORY => 4,
+        EntryType::BOOTLOADER_RECLAIMABLE => 5,
+        EntryType::KERNEL_AND_MODULES => 6,
+        EntryType::FRAMEBUFFER => 7,
+        _ => 8,
+    }
+}
+
+pub const MEMMAP_TYPE_NAMES: [&str; MEMMAP_TYPES] = [
+    "USABLE",
+    "RESERVED",
+    "ACPI_RECLAIMABLE",
+    "ACPI_NVS",
+    "BAD_MEMORY",
+    "BOOTLOADER_RECLAIMABLE",
+    "KERNEL_AND_MODULES",
+    "FRAMEBUFFER",
+    "UNKNOWN",
+];
+
+// How many of the frames touched by [base, base + length) fall in each zone.
+fn zone_frames(base: u64, length: u64) -> [usize; ZONES] {
+    let end = base + length;
+    Zone::ALL.map(|zone| {
+        let overlap = end.min(zone.end()).saturating_sub(base.max(zone.start()));
+        (overlap as usize).div_ceil(FRAME_SIZE)
+    })
+}
+
+/// A snapshot of the PMM's counters, see `Pmm::meminfo`.
+pub struct MemInfo {
+    pub zones: [FrameStats; ZONES],
+    pub types: [FrameStats; MEMMAP_TYPES],
+}
+
+impl MemInfo {
+    /// The counts of all zones together.
+    pub fn total(&self) -> FrameStats {
+        let mut total = FrameStats::default();
+        for zone in &self.zones {
+            total.add(zone);
+        }
+        total
+    }
+}
+
+/// The links of a free buddy block, kept in its first frame. Physical addresses, 0 for none.
+#[repr(C)]
+struct FreeBlock {
+    next: u64,
+    prev: u64,
+}
+
+/// Physical memory is tracked twice: by a bitmap with one bit per frame (set while in use), and
+/// by a buddy allocator with one free list per zone and order, whose blocks split and merge with
+/// their buddies in O(log n). The buddy allocator serves the allocations unless the `bitmap-pmm`
+/// feature falls back to a linear scan of the bitmap, in which case its free lists stay empty.
 pub struct Pmm {
     bitmap: &'static mut [u64],
+    // the order of the free block starting at each frame, NOT_A_FREE_BLOCK for any other frame.
+    orders: &'static mut [u8],
+    free_lists: [[u64; MAX_ORDER + 1]; ZONES],
+    zones: [FrameStats; ZONES],
+    // `free` is only counted by `meminfo`, which walks the bitmap for it.
+    types: [FrameStats; MEMMAP_TYPES],
+    hhdm: u64,
 }
 
+/// The allocator for all physical memory, usable once `init` has run. Do not allocate from the
+/// kernel heap while holding this lock, growing the heap needs it too.
+pub static PMM: Spinlock<Pmm> = Spinlock::new(Pmm {
+    bitmap: &mut [],
+    orders: &mut [],
+    free_lists: [[0; MAX_ORDER + 1]; ZONES],
+    zones: [FrameStats {
+        total: 0,
+        managed: 0,
+        free: 0,
+    }; ZONES],
+    types: [FrameStats {
+        total: 0,
+        managed: 0,
+        free: 0,
+    }; MEMMAP_TYPES],
+    hhdm: 0,
+});
+
 #[derive(Debug)]
 #[allow(dead_code)]
 pub struct PmmAllocError;
@@ -51,40 +234,43 @@ pub struct PmmAllocError;
 impl Pmm {
     fn set_free(&mut self, base: Frame) {
         let frame_start = base.phy_ptr as usize / FRAME_SIZE;
-        let n_frames = base.size  / FRAME_SIZE;
+        let n_frames = base.size / FRAME_SIZE;
 
-        kprintln!(
-            "n_frames: {} phy_ptr: {:#x} bmp_area: {:#x}",
-            n_frames,
-            base.phy_ptr,
-            self.bitmap.len() * 64
-        );
         for i in 0..n_frames {
             let frame = frame_start + i;
             let frame_big_idx = frame / 64;
             let frame_sma_idx = frame % 64;
 
-
-	    kprintln!(
-		"bitmap: {:#b}",
-		self.bitmap[frame_big_idx as usize],
-	    );
-
             self.bitmap[frame_big_idx as usize] &= !((1 as u64) << frame_sma_idx);
         }
     }
 
-    fn find_free_frame(&self, n_frames: usize) -> Option<Frame> {
+    // Finds `n_frames` free frames in a row within the frames `first..limit`, the first one
+    // being a multiple of `align_frames`.
+    fn find_free_frame(
+        &self,
+        n_frames: usize,
+        first: usize,
+        limit: usize,
+        align_frames: usize,
+    ) -> Option<Frame> {
         if n_frames == 0 {
             return None;
         }
 
         let mut zero_count = 0;
 
-        for i in 0..self.bitmap.len() {
+        for i in first / 64..self.bitmap.len() {
             let super_frame = self.bitmap[i];
             if super_frame != 0xffffffffffffffff {
                 for j in 0..64 {
+                    if i * 64 + j >= limit {
+                        return None;
+                    }
+                    if i * 64 + j < first {
+                        continue;
+                    }
+
                     if ((super_frame >> j) & 1) == 0 {
                         zero_count += 1;
                     } else {
@@ -94,6 +280,9 @@ impl Pmm {
                     if zero_count >= n_frames {
 
                         let zero_idx: usize = (i * 64 + j) - (n_frames - 1);
+                        if zero_idx % align_frames != 0 {
+                            continue;
+                        }
 			let zero_idx = zero_idx as u64;
 			
                         return Some(Frame::from_u64(
@@ -109,6 +298,10 @@ impl Pmm {
         return None;
     }
 
+    fn is_used(&self, frame: usize) -> bool {
+        (self.bitmap[frame / 64] >> (frame % 64)) & 1 == 1
+    }
+
     fn set_used(&mut self, frame_ptr: &Frame) {
         let frame_start = frame_ptr.phy_ptr / FRAME_SIZE as u64;
         let n_frames = frame_ptr.size as usize / FRAME_SIZE;
@@ -122,27 +315,230 @@ impl Pmm {
         }
     }
 
+    fn free_block(&self, phys: u64) -> *mut FreeBlock {
+        (phys + self.hhdm) as *mut FreeBlock
+    }
+
+    fn push_block(&mut self, phys: u64, order: usize) {
+        let free_list = &mut self.free_lists[Zone::of(phys) as usize][order];
+        let head = *free_list;
+        *free_list = phys;
+
+        unsafe {
+            *self.free_block(phys) = FreeBlock {
+                next: head,
+                prev: 0,
+            };
+            if head != 0 {
+                (*self.free_block(head)).prev = phys;
+            }
+        }
+
+        self.orders[phys as usize / FRAME_SIZE] = order as u8;
+    }
+
+    fn unlink_block(&mut self, phys: u64, order: usize) {
+        let FreeBlock { next, prev } = unsafe { ptr::read(self.free_block(phys)) };
+        unsafe {
+            if next != 0 {
+                (*self.free_block(next)).prev = prev;
+            }
+            if prev != 0 {
+                (*self.free_block(prev)).next = next;
+            }
+        }
+        let free_list = &mut self.free_lists[Zone::of(phys) as usize][order];
+        if *free_list == phys {
+            *free_list = next;
+        }
+
+        self.orders[phys as usize / FRAME_SIZE] = NOT_A_FREE_BLOCK;
+    }
+
+    // Takes a block of 2^order frames off the smallest free list of `zone` that has one, splitting
+    // it down and putting the halves it does not need back.
+    fn buddy_alloc(&mut self, zone: Zone, order: usize) -> Option<u64> {
+        let free_lists = &self.free_lists[zone as usize];
+        let mut block_order = (order..=MAX_ORDER).find(|&o| free_lists[o] != 0)?;
+        let phys = free_lists[block_order];
+        self.unlink_block(phys, block_order);
+
+        while block_order > order {
+            block_order -= 1;
+            self.push_block(phys + (FRAME_SIZE << block_order) as u64, block_order);
+        }
+        Some(phys)
+    }
+
+    // Puts a block of 2^order frames back, merging it with its buddy for as long as that one is
+    // free too.
+    fn buddy_free(&mut self, mut phys: u64, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = phys ^ (FRAME_SIZE << order) as u64;
+            let buddy_frame = buddy as usize / FRAME_SIZE;
+            if self.orders.get(buddy_frame) != Some(&(order as u8)) {
+                break;
+            }
+
+            self.unlink_block(buddy, order);
+            phys = phys.min(buddy);
+            order += 1;
+        }
+        self.push_block(phys, order);
+    }
+
+    // Frees `n_frames` frames at `phys` as the biggest naturally aligned blocks they split into.
+    fn buddy_free_range(&mut self, mut phys: u64, mut n_frames: usize) {
+        while n_frames > 0 {
+            let frame = phys as usize / FRAME_SIZE;
+            let order = (0..=MAX_ORDER)
+                .rev()
+                .find(|&o| frame % (1 << o) == 0 && (1 << o) <= n_frames)
+                .unwrap();
+
+            self.buddy_free(phys, order);
+            phys += (FRAME_SIZE << order) as u64;
+            n_frames -= 1 << order;
+        }
+    }
+
+    // Allocates exactly `n_frames` frames in `zone`, aligned to `align_frames`: the block they
+    // round up to, minus its unused tail.
+    fn buddy_alloc_frames(
+        &mut self,
+        zone: Zone,
+        n_frames: usize,
+        align_frames: usize,
+    ) -> Option<Frame> {
+        if n_frames == 0 {
+            return None;
+        }
+
+        // blocks are naturally aligned to their size.
+        let order = n_frames
+            .max(align_frames)
+            .next_power_of_two()
+            .trailing_zeros() as usize;
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        let phys = self.buddy_alloc(zone, order)?;
+        let tail = phys + (n_frames * FRAME_SIZE) as u64;
+        self.buddy_free_range(tail, (1 << order) - n_frames);
+
+        Some(Frame::from_u64(phys, n_frames * FRAME_SIZE))
+    }
+
+    /// Hands out `n_frames` physically contiguous frames from anywhere, preferably from memory
+    /// that devices with limited addressing have no use for.
     pub fn alloc_frame(&mut self, n_frames: usize) -> Result<Frame, PmmAllocError> {
-        match self.find_free_frame(n_frames) {
+        self.alloc_frame_in(Zone::Normal, n_frames, FRAME_SIZE)
+    }
+
+    /// Hands out `n_frames` physically contiguous frames from `zone` or a lower one, whose
+    /// physical address is a multiple of `align` bytes, a power of two. The buddy allocator
+    /// only has contiguous runs of up to 2^MAX_ORDER frames.
+    pub fn alloc_frame_in(
+        &mut self,
+        zone: Zone,
+        n_frames: usize,
+        align: usize,
+    ) -> Result<Frame, PmmAllocError> {
+        if !align.is_power_of_two() {
+            return Err(PmmAllocError {});
+        }
+        let align_frames = align.div_ceil(FRAME_SIZE);
+
+        // the requested zone first, lower ones only once it is exhausted, like the free lists.
+        let frame = if cfg!(feature = "bitmap-pmm") {
+            let total = self.orders.len() as u64 * FRAME_SIZE as u64;
+            Zone::ALL[..=zone as usize].iter().rev().find_map(|&zone| {
+                let first = (zone.start().min(total) / FRAME_SIZE as u64) as usize;
+                let limit = (zone.end().min(total) / FRAME_SIZE as u64) as usize;
+                self.find_free_frame(n_frames, first, limit, align_frames)
+            })
+        } else {
+            Zone::ALL[..=zone as usize]
+                .iter()
+                .rev()
+                .find_map(|&zone| self.buddy_alloc_frames(zone, n_frames, align_frames))
+        };
+
+        match frame {
             None => Err(PmmAllocError {}),
             Some(frame) => {
                 self.set_used(&frame);
+                let frames = zone_frames(frame.phy_ptr, frame.size as u64);
+                for (stats, n) in self.zones.iter_mut().zip(frames) {
+                    stats.free -= n;
+                }
                 Ok(frame)
             }
         }
     }
 
     pub fn dealloc_frame(&mut self, frame_ptr: Frame) {
+        let first = frame_ptr.phy_ptr as usize / FRAME_SIZE;
+        let n_frames = frame_ptr.size / FRAME_SIZE;
+        assert!(
+            (first..first + n_frames).all(|frame| self.is_used(frame)),
+            "freeing frames at {:#x} that are already free!",
+            frame_ptr.phy_ptr
+        );
+
+        if !cfg!(feature = "bitmap-pmm") {
+            self.buddy_free_range(frame_ptr.phy_ptr, n_frames);
+        }
+        let frames = zone_frames(frame_ptr.phy_ptr, frame_ptr.size as u64);
+        for (stats, n) in self.zones.iter_mut().zip(frames) {
+            stats.free += n;
+        }
         self.set_free(frame_ptr);
     }
+
+    // Hands a region of the memory map of type `kind` to both allocators.
+    fn add_free_region(&mut self, base: u64, length: usize, kind: EntryType) {
+        // the bitmap allocator overwrites free frames, so no buddy links may live in them.
+        if !cfg!(feature = "bitmap-pmm") {
+            self.buddy_free_range(base, length / FRAME_SIZE);
+        }
+        self.set_free(Frame::from_u64(base, length));
+
+        for (stats, n) in self.zones.iter_mut().zip(zone_frames(base, length as u64)) {
+            stats.managed += n;
+            stats.free += n;
+        }
+        self.types[type_index(kind)].managed += length / FRAME_SIZE;
+    }
+
+    /// Frees the page aligned region at `base`, which held boot data of type `kind` that is no
+    /// longer needed.
+    pub fn reclaim(&mut self, base: u64, length: usize, kind: EntryType) {
+        self.add_free_region(base, length, kind);
+    }
+
+    /// Returns how many frames each zone and each memory map type has, and how many of them
+    /// are free, used and reserved.
+    pub fn meminfo(&self) -> MemInfo {
+        let mut types = self.types;
+
+        for region in boot::info().memmap() {
+            let first = (region.base as usize).div_ceil(FRAME_SIZE);
+            let end = ((region.base + region.length) as usize / FRAME_SIZE).min(self.orders.len());
+            types[type_index(region.kind)].free += (first..end.max(first))
+                .filter(|&frame| !self.is_used(frame))
+                .count();
+        }
+
+        MemInfo {
+            zones: self.zones,
+            types,
+        }
+    }
 }
 
-pub fn init(
-    memmap_request: &limine::request::MemoryMapRequest,
-    hhdm_request: &limine::request::HhdmRequest,
-) -> Pmm {
-    let mmap_response = memmap_request.get_response().unwrap();
-    let entries = mmap_response.entries();
+pub fn init(entries: &[MemoryRegion], hhdm: u64) {
     print_mmap(entries);
 
     let mut biggest_usable_base = 0;
@@ -150,25 +546,25 @@ pub fn init(
     let mut highest_frame_top = 0;
 
     for entry in entries {
-        use limine::memory_map::EntryType;
-        match entry.entry_type {
+        match entry.kind {
             EntryType::USABLE => {
                 if entry.length > biggest_usable_length {
                     biggest_usable_base = entry.base;
                     biggest_usable_length = entry.length;
                 }
-
-                let frame_top = entry.base + entry.length;
-                if frame_top > highest_frame_top {
-                    highest_frame_top = frame_top;
-                }
             }
+            // handed to the PMM later by `boot::reclaim`.
+            EntryType::BOOTLOADER_RECLAIMABLE | EntryType::ACPI_RECLAIMABLE => {}
             _ => continue,
         }
+
+        let frame_top = entry.base + entry.length;
+        if frame_top > highest_frame_top {
+            highest_frame_top = frame_top;
+        }
     }
 
-    let hhdm = hhdm_request.get_response().unwrap();
-    let bmp_base = biggest_usable_base + hhdm.offset();
+    let bmp_base = biggest_usable_base + hhdm;
 
     let total_frames: usize = (highest_frame_top as usize / FRAME_SIZE) + 1; // Total number of frames
 
@@ -177,84 +573,100 @@ pub fn init(
         unsafe { slice::from_raw_parts_mut(bmp_base as *mut u64, bmp_len) };
 
     kprintln!(
-	"highest_frame_top: {:#x} biggest_usable_base: {:#x} biggest_usable_len: {:#x} bmp_len: {:#x}",
-	highest_frame_top,
-	biggest_usable_base,
-	biggest_usable_length,
-	bmp_len * 64
+        "pmm: tracking {:#x} frames, bitmap at {:#x}",
+        total_frames,
+        biggest_usable_base
     );
 
     bmp.fill(0xffff_ffff_ffff_ffff);
 
-    let mut pmm = Pmm { bitmap: bmp };
+    // the buddy allocator's order of every frame comes right after the bitmap.
+    let orders_base = bmp_base + (bmp_len * size_of::<u64>()) as u64;
+    let orders: &'static mut [u8] =
+        unsafe { slice::from_raw_parts_mut(orders_base as *mut u8, total_frames) };
+    orders.fill(NOT_A_FREE_BLOCK);
+
+    let mut pmm = Pmm {
+        bitmap: bmp,
+        orders,
+        free_lists: [[0; MAX_ORDER + 1]; ZONES],
+        zones: [FrameStats::default(); ZONES],
+        types: [FrameStats::default(); MEMMAP_TYPES],
+        hhdm,
+    };
 
     for entry in entries {
-        use core::mem::size_of;
-        use limine::memory_map::EntryType;
+        let frames = zone_frames(entry.base, entry.length);
+        for (stats, n) in pmm.zones.iter_mut().zip(frames) {
+            stats.total += n;
+        }
+        pmm.types[type_index(entry.kind)].total += (entry.length as usize).div_ceil(FRAME_SIZE);
 
-        match entry.entry_type {
+        match entry.kind {
             EntryType::USABLE => {
                 let base = entry.base;
                 let length = entry.length as usize;
 
-                if base == biggest_usable_base {
-                    pmm.set_free(Frame::from_u64(
-                        base + (bmp_len as u64) * (size_of::<u64>() as u64),
-                        (length - (pmm.bitmap.len() * 64)) / FRAME_SIZE as usize,
-                    ));
+                // the bitmap and the orders live at the start of the biggest region, keep them
+                // used, and never hand out the null frame.
+                let reserved = if base == biggest_usable_base {
+                    (bmp_len * size_of::<u64>() + total_frames).next_multiple_of(FRAME_SIZE)
+                } else if base == 0 {
+                    FRAME_SIZE
                 } else {
-                    pmm.set_free(Frame::from_u64(
-                        base,
-                        (length / FRAME_SIZE).try_into().unwrap(),
-                    ));
-                }
+                    0
+                };
+
+                pmm.add_free_region(base + reserved as u64, length - reserved, entry.kind);
             }
             _ => continue,
         }
     }
 
-    // for null frame
-    pmm.bitmap[0] = pmm.bitmap[0] | 1;
-    kprintln!("{:b}", pmm.bitmap[0]);
-    return pmm;
+    *PMM.lock() = pmm;
 }
 
-fn print_mmap(entries: &[&limine::memory_map::Entry]) {
+fn print_mmap(entries: &[MemoryRegion]) {
     for entry in entries {
-        use limine::memory_map::EntryType;
-        match entry.entry_type {
-            EntryType::USABLE => {
-                kprintln!("{:#x} {:#x} USABLE", entry.base, entry.length);
-            }
-            EntryType::RESERVED => {
-                kprintln!("{:#x} {:#x} RESERVED", entry.base, entry.length);
-            }
-            EntryType::ACPI_RECLAIMABLE => {
-                kprintln!("{:#x} {:#x} ACPI_RECLAIMABLE ", entry.base, entry.length);
-            }
-            EntryType::ACPI_NVS => {
-                kprintln!("{:#x} {:#x} ACPI_NVS ", entry.base, entry.length);
-            }
-            EntryType::BAD_MEMORY => {
-                kprintln!("{:#x} {:#x} BAD_MEMORY", entry.base, entry.length);
-            }
-            EntryType::BOOTLOADER_RECLAIMABLE => {
-                kprintln!(
-                    "{:#x} {:#x} BOOTLOADER_RECLAIMABLE",
-                    entry.base,
-                    entry.length
-                );
-            }
-            EntryType::KERNEL_AND_MODULES => {
-                kprintln!("{:#x} {:#x} KERNEL_AND_MODULES", entry.base, entry.length);
-            }
-            EntryType::FRAMEBUFFER => {
-                kprintln!("{:#x} {:#x} FRAMEBUFFER", entry.base, entry.length);
-            }
+        kprintln!(
+            "pmm: {:#018x} {:#x} {}",
+            entry.base,
+            entry.length,
+            MEMMAP_TYPE_NAMES[type_index(entry.kind)]
+        );
+    }
+}
 
-            _ => {
-                todo!("Memory type is unidentifiable!");
-            }
+fn print_stats(name: &str, stats: &FrameStats) {
+    const KIB_PER_FRAME: usize = FRAME_SIZE / 1024;
+
+    kprintln!(
+        "{:<24}{:>12} kB total{:>12} kB free{:>12} kB used{:>12} kB reserved",
+        name,
+        stats.total * KIB_PER_FRAME,
+        stats.free * KIB_PER_FRAME,
+        stats.used() * KIB_PER_FRAME,
+        stats.reserved() * KIB_PER_FRAME
+    );
+}
+
+/// Prints a summary of the physical memory, like /proc/meminfo.
+pub fn print_meminfo() {
+    let info = PMM.lock().meminfo();
+    let total = info.total();
+    let kib = |frames: usize| frames * (FRAME_SIZE / 1024);
+
+    kprintln!("MemTotal:    {:>12} kB", kib(total.managed));
+    kprintln!("MemFree:     {:>12} kB", kib(total.free));
+    kprintln!("MemUsed:     {:>12} kB", kib(total.used()));
+    kprintln!("MemReserved: {:>12} kB", kib(total.reserved()));
+
+    for zone in Zone::ALL {
+        print_stats(zone.name(), &info.zones[zone as usize]);
+    }
+    for (name, stats) in MEMMAP_TYPE_NAMES.iter().zip(&info.types) {
+        if stats.total != 0 {
+            print_stats(name, stats);
         }
     }
 }
@@ -0,0 +1,218 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use core::arch::asm;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::idt::Regs;
+use crate::kprintln;
+use crate::percpu;
+use crate::smp::{self, MAX_CPUS};
+use crate::sync::Spinlock;
+use crate::task::{Task, TaskState};
+use crate::vmm;
+use crate::{percpu_read, percpu_write};
+
+/// One CPU's share of the scheduler. Each CPU switches between the tasks queued on its own run
+/// queue every time its timer fires, and takes one from another CPU's once it runs out. The
+/// context that calls `idle_loop` on a CPU becomes its idle task which only runs when nothing
+/// else can.
+pub struct RunQueue {
+    tasks: VecDeque<Box<Task>>,
+    current: Option<Box<Task>>,
+    // the task switched away from is only queued again (or destroyed once finished) on the next
+    // switch, once this CPU is off its kernel stack and no other CPU can be on it yet.
+    previous: Option<Box<Task>>,
+    idle_regs: *mut Regs,
+}
+
+// a run queue is only ever touched with interrupts disabled, under its lock.
+unsafe impl Send for RunQueue {}
+
+impl RunQueue {
+    const fn new() -> RunQueue {
+        RunQueue {
+            tasks: VecDeque::new(),
+            current: None,
+            previous: None,
+            idle_regs: ptr::null_mut(),
+        }
+    }
+
+    fn take_queued(&mut self) -> Option<Box<Task>> {
+        self.tasks
+            .iter()
+            .position(|task| task.state == TaskState::Queued)
+            .and_then(|position| self.tasks.remove(position))
+    }
+}
+
+// indexed by CPU id, each CPU reaches its own through its `PerCpu`.
+static RUN_QUEUES: [Spinlock<RunQueue>; MAX_CPUS] =
+    [const { Spinlock::new(RunQueue::new()) }; MAX_CPUS];
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The run queue of the CPU with id `cpu_id`.
+pub fn run_queue(cpu_id: usize) -> &'static Spinlock<RunQueue> {
+    &RUN_QUEUES[cpu_id]
+}
+
+fn this_run_queue() -> &'static Spinlock<RunQueue> {
+    let run_queue = percpu_read!(run_queue) as *const Spinlock<RunQueue>;
+    unsafe { &*run_queue }
+}
+
+/// Queues `task` on the CPU with the fewest tasks.
+pub fn queue_task(task: Task) {
+    let mut task = Box::new(task);
+    task.id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    task.state = TaskState::Queued;
+
+    // one lock at a time: a guard dropped while another is held would re-enable interrupts
+    // under it. The lengths may be stale by the time we push, which only costs balance.
+    let shortest = RUN_QUEUES[..smp::cpu_count()]
+        .iter()
+        .min_by_key(|run_queue| run_queue.lock().tasks.len())
+        .unwrap();
+    shortest.lock().tasks.push_back(task);
+}
+
+/// Id of the task running on the calling CPU, 0 for the idle task.
+pub fn current_task_id() -> u64 {
+    let task = percpu_read!(current_task) as *const Task;
+    unsafe { task.as_ref() }.map_or(0, |task| task.id)
+}
+
+/// Whether a fault at `addr` hit the guard page below the kernel stack of the task running on
+/// the calling CPU. Takes no lock, the double fault handler asks.
+pub fn current_task_overflowed(addr: u64) -> bool {
+    let task = percpu_read!(current_task) as *const Task;
+    unsafe { task.as_ref() }.is_some_and(|task| task.overflowed_kernel_stack(addr))
+}
+
+// Takes a queued task off another CPU's run queue. Never waits for a lock, as that CPU may be
+// trying to steal from us at the same time.
+fn steal() -> Option<Box<Task>> {
+    let cpu_id = percpu::cpu_id();
+    let cpus = smp::cpu_count();
+
+    (1..cpus)
+        .map(|offset| &RUN_QUEUES[(cpu_id + offset) % cpus])
+        .find_map(|run_queue| run_queue.try_lock()?.take_queued())
+}
+
+/// Called from the timer interrupt with the register frame of whatever was interrupted.
+/// Returns the frame of the task that should run next.
+pub fn schedule(regs: *mut Regs) -> *mut Regs {
+    // a CPU still bringing itself or the kernel up keeps running whatever it was doing.
+    if percpu_read!(scheduling) == 0 {
+        return regs;
+    }
+    let mut run_queue = this_run_queue().lock();
+
+    if let Some(task) = run_queue.previous.take() {
+        if task.state == TaskState::Finished {
+            task.destroy();
+        } else {
+            run_queue.tasks.push_back(task);
+        }
+    }
+
+    let outgoing = match run_queue.current.take() {
+        Some(mut task) => {
+            task.regs = regs;
+            if task.state == TaskState::Running {
+                task.state = TaskState::Queued;
+            }
+            Some(task)
+        }
+        None => {
+            run_queue.idle_regs = regs;
+            None
+        }
+    };
+
+    let mut next = run_queue.take_queued().or_else(steal);
+
+    // nothing else to run, the outgoing task can go on right here as this CPU is on its stack.
+    if next.is_none()
+        && outgoing
+            .as_ref()
+            .is_some_and(|task| task.state == TaskState::Queued)
+    {
+        next = outgoing;
+    } else {
+        run_queue.previous = outgoing;
+    }
+
+    match next {
+        Some(mut task) => {
+            task.state = TaskState::Running;
+            task.activate_address_space();
+
+            // interrupts and syscalls from ring 3 have to land on the task's own kernel stack.
+            smp::set_kernel_stack(task.kernel_stack_top());
+            percpu_write!(current_task, &*task as *const Task as u64);
+
+            let regs = task.regs;
+            run_queue.current = Some(task);
+            regs
+        }
+        None => {
+            vmm::switch_to(&vmm::kernel_address_space());
+            percpu_write!(current_task, 0);
+            run_queue.idle_regs
+        }
+    }
+}
+
+// Marks the task running on the calling CPU as finished, the next switch away from it reaps it.
+// Returns its id.
+fn finish_current() -> u64 {
+    let mut run_queue = this_run_queue().lock();
+    let task = run_queue
+        .current
+        .as_mut()
+        .expect("the idle task cannot exit!");
+
+    task.state = TaskState::Finished;
+    task.id
+}
+
+/// Ends the running task with `status`. Its resources are freed once the timer switched away
+/// from it, so this just waits for that to happen.
+pub fn exit_current(status: i32) -> ! {
+    let id = finish_current();
+    kprintln!("task {} exited with status {}", id, status);
+
+    loop {
+        unsafe { asm!("sti", "hlt") };
+    }
+}
+
+/// Ends the running task from inside a syscall, without a status: it was killed. Waits for the
+/// timer to switch away like `exit_current`.
+pub fn abort_current() -> ! {
+    finish_current();
+
+    loop {
+        unsafe { asm!("sti", "hlt") };
+    }
+}
+
+/// Ends the running task after a fault it caused in ring 3, `regs` being the fault's frame.
+/// Returns the frame of the task to run instead.
+pub fn kill_current(regs: *mut Regs) -> *mut Regs {
+    finish_current();
+    schedule(regs)
+}
+
+/// Hands the calling CPU over to the queued tasks, the caller becomes its idle task.
+pub fn idle_loop() -> ! {
+    percpu_write!(scheduling, 1);
+
+    loop {
+        unsafe { asm!("sti", "hlt") };
+    }
+}
@@ -0,0 +1,286 @@
+use alloc::alloc::{alloc_zeroed, Layout};
+use core::arch::asm;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+use crate::apic;
+use crate::asa_limine;
+use crate::gdt::{self, GuardedStack};
+use crate::idt::{self, Regs};
+use crate::kprintln;
+use crate::percpu::{self, PerCpu};
+use crate::percpu_write;
+use crate::scheduler;
+use crate::sync::Spinlock;
+use crate::syscall;
+use crate::vmm;
+
+pub const MAX_CPUS: usize = 16;
+
+/// Everything a CPU needs to itself: the stack it boots and idles on, its GDT, TSS and
+/// exception stacks, and the `PerCpu` its gs base points at.
+#[repr(C)]
+struct Cpu {
+    percpu: PerCpu,
+    lapic_id: u32,
+    kernel_stack: GuardedStack,
+    tables: gdt::CpuTables,
+}
+
+// the bootstrap processor's lives in .bss as there is no heap yet when it needs it.
+static mut BSP: Cpu = Cpu {
+    percpu: PerCpu::new(),
+    lapic_id: 0,
+    kernel_stack: GuardedStack::new(),
+    tables: gdt::CpuTables::new(),
+};
+
+// indexed by the CPU index, the BSP being 0. Entries below CPU_COUNT are set and never change.
+static CPUS: [AtomicPtr<Cpu>; MAX_CPUS] = [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS];
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// APs that made it to the idle loop.
+static APS_ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+// set if Limine still holds APs that were not started, spinning in bootloader reclaimable memory.
+static APS_PARKED: AtomicBool = AtomicBool::new(false);
+
+// bit i is set once CPU i takes TLB shootdown IPIs, which only happens with the local APICs.
+static SHOOTDOWN_CPUS: AtomicU64 = AtomicU64::new(0);
+
+// the kernel page every CPU whose bit is set in SHOOTDOWN_TARGETS still has to drop from its
+// TLB. The lock makes it one shootdown at a time.
+static SHOOTDOWN: Spinlock<()> = Spinlock::new(());
+static SHOOTDOWN_ADDRESS: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_TARGETS: AtomicU64 = AtomicU64::new(0);
+
+const TLB_SHOOTDOWN_VECTOR: u8 = 0xf0;
+
+fn cpu(index: usize) -> *mut Cpu {
+    CPUS[index].load(Ordering::Acquire)
+}
+
+/// Number of CPUs started so far, the BSP included.
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::Acquire)
+}
+
+fn register(cpu: *mut Cpu) -> usize {
+    let index = CPU_COUNT.load(Ordering::Relaxed);
+    unsafe {
+        (*cpu).percpu.cpu_id = index as u64;
+        (*cpu).percpu.run_queue = scheduler::run_queue(index) as *const _ as u64;
+    }
+
+    CPUS[index].store(cpu, Ordering::Release);
+    CPU_COUNT.store(index + 1, Ordering::Release);
+    index
+}
+
+/// Sets the stack the calling CPU switches to when entering the kernel from ring 3, be it
+/// through an interrupt or `syscall`.
+pub fn set_kernel_stack(kernel_stack_ptr: u64) {
+    let cpu = cpu(percpu::cpu_id());
+    unsafe { gdt::set_kernel_stack(&mut (*cpu).tables, kernel_stack_ptr) };
+    percpu_write!(kernel_stack_ptr, kernel_stack_ptr);
+}
+
+/// Top of the stack the BSP runs on from `kmain` onwards.
+pub fn bsp_kernel_stack_top() -> u64 {
+    gdt::stack_top(unsafe { &raw const BSP.kernel_stack })
+}
+
+/// Sets up the GDT, TSS and gs base of the BSP, which must already run on its kernel stack.
+pub unsafe fn init_bsp() {
+    let bsp = &raw mut BSP;
+    let kernel_stack_ptr = bsp_kernel_stack_top();
+
+    (*bsp).percpu.kernel_stack_ptr = kernel_stack_ptr;
+    gdt::init(&raw mut (*bsp).tables, kernel_stack_ptr);
+    register(bsp);
+    percpu::init(&raw mut (*bsp).percpu);
+}
+
+// every stack of `cpu` with a guard page, named as in the fault reports.
+fn guarded_stacks(cpu: *const Cpu) -> impl Iterator<Item = (&'static str, *const GuardedStack)> {
+    let cpu = unsafe { &*cpu };
+    [("kernel", &raw const cpu.kernel_stack)]
+        .into_iter()
+        .chain(cpu.tables.guarded_stacks())
+}
+
+/// Unmaps the guard pages below the BSP's stacks, which needs the VMM.
+pub fn guard_bsp_stacks() {
+    for (name, stack) in guarded_stacks(cpu(0)) {
+        gdt::guard_stack(name, stack);
+    }
+}
+
+/// The stack that overflowed and the index of the CPU it belongs to if a fault hit `addr`, i.e.
+/// the one whose guard page it is in.
+pub fn overflowed_stack(addr: u64) -> Option<(&'static str, usize)> {
+    (0..CPU_COUNT.load(Ordering::Acquire)).find_map(|index| {
+        guarded_stacks(cpu(index))
+            .find(|&(_, stack)| gdt::hits_guard_page(stack, addr))
+            .map(|(name, _)| (name, index))
+    })
+}
+
+// Where Limine drops each AP, on a stack of its own in bootloader memory and with interrupts off.
+unsafe extern "C" fn ap_entry(info: &limine::smp::Cpu) -> ! {
+    let cpu = (1..CPU_COUNT.load(Ordering::Acquire))
+        .map(cpu)
+        .find(|&cpu| (*cpu).lapic_id == info.lapic_id)
+        .unwrap();
+
+    vmm::switch_to(&vmm::kernel_address_space());
+
+    asm!(
+        "mov rsp, {}",
+        "call {}",
+        in(reg) gdt::stack_top(&raw const (*cpu).kernel_stack),
+        sym ap_main,
+        in("rdi") cpu,
+        options(noreturn)
+    );
+}
+
+extern "C" fn ap_main(cpu: *mut Cpu) -> ! {
+    unsafe {
+        gdt::init(&raw mut (*cpu).tables, (*cpu).percpu.kernel_stack_ptr);
+        idt::load();
+        percpu::init(&raw mut (*cpu).percpu);
+    }
+    syscall::init();
+
+    // the BSP's PIT only interrupts the BSP, every AP preempts its tasks with its own timer.
+    apic::init_ap();
+    apic::start_timer(idt::TIMER_VECTOR);
+
+    kprintln!(
+        "smp: CPU {} (local APIC {}) is up",
+        percpu::cpu_id(),
+        apic::lapic_id()
+    );
+    SHOOTDOWN_CPUS.fetch_or(1 << percpu::cpu_id(), Ordering::AcqRel);
+    APS_ONLINE.fetch_add(1, Ordering::Release);
+
+    scheduler::idle_loop();
+}
+
+/// Starts every AP Limine found, each on its own stacks and tables, and waits until all of them
+/// reached the idle loop. Needs the heap and the APICs.
+pub fn start_aps() {
+    let Some(response) = asa_limine::smp() else {
+        kprintln!("smp: no response from the bootloader, running on the BSP only");
+        return;
+    };
+    if !apic::is_enabled() {
+        kprintln!("smp: no local APIC, running on the BSP only");
+        APS_PARKED.store(response.cpus().len() > 1, Ordering::Release);
+        return;
+    }
+
+    apic::calibrate_timer(idt::TIMER_HZ);
+
+    if let Err(e) = idt::register_irq(TLB_SHOOTDOWN_VECTOR, tlb_shootdown_interrupt) {
+        kprintln!(
+            "smp: no TLB shootdown vector, running on the BSP only: {:?}",
+            e
+        );
+        APS_PARKED.store(response.cpus().len() > 1, Ordering::Release);
+        return;
+    }
+    // the APs' shootdowns reach the BSP through its local APIC id.
+    unsafe { (*cpu(0)).lapic_id = apic::lapic_id() };
+    SHOOTDOWN_CPUS.fetch_or(1 << percpu::cpu_id(), Ordering::AcqRel);
+
+    for info in response.cpus() {
+        if info.lapic_id == response.bsp_lapic_id() {
+            continue;
+        }
+        if CPU_COUNT.load(Ordering::Relaxed) == MAX_CPUS {
+            kprintln!("smp: only using the first {} CPUs", MAX_CPUS);
+            APS_PARKED.store(true, Ordering::Release);
+            break;
+        }
+
+        // far too big for the stack Box::new would build it on first.
+        let cpu = unsafe { alloc_zeroed(Layout::new::<Cpu>()) } as *mut Cpu;
+        if cpu.is_null() {
+            kprintln!("smp: out of memory for CPU {}", info.id);
+            APS_PARKED.store(true, Ordering::Release);
+            break;
+        }
+
+        unsafe {
+            (*cpu).lapic_id = info.lapic_id;
+            (*cpu).percpu.kernel_stack_ptr = gdt::stack_top(&raw const (*cpu).kernel_stack);
+        }
+        for (name, stack) in guarded_stacks(cpu) {
+            gdt::guard_stack(name, stack);
+        }
+
+        register(cpu);
+        info.goto_address.write(ap_entry);
+    }
+
+    let aps = CPU_COUNT.load(Ordering::Acquire) - 1;
+    while APS_ONLINE.load(Ordering::Acquire) < aps {
+        core::hint::spin_loop();
+    }
+    kprintln!("smp: {} CPUs online", aps + 1);
+}
+
+/// Whether some APs were left in Limine's hands, still running on its stacks and page tables.
+pub fn aps_parked() -> bool {
+    APS_PARKED.load(Ordering::Acquire)
+}
+
+/// Makes every other CPU drop its TLB entry for the kernel page at `virt` and waits until all
+/// of them did, the caller flushes its own. Must not be called while holding a lock other CPUs
+/// may spin on, as they take the IPI only once they got it.
+pub fn tlb_shootdown(virt: u64) {
+    // other CPUs may be waiting for the lock with interrupts off, serve them meanwhile.
+    let _shootdown = loop {
+        if let Some(guard) = SHOOTDOWN.try_lock() {
+            break guard;
+        }
+        flush_shot_down_page();
+        core::hint::spin_loop();
+    };
+
+    let targets = SHOOTDOWN_CPUS.load(Ordering::Acquire) & !(1 << percpu::cpu_id());
+    if targets == 0 {
+        return;
+    }
+
+    SHOOTDOWN_ADDRESS.store(virt, Ordering::Relaxed);
+    SHOOTDOWN_TARGETS.store(targets, Ordering::Release);
+    for index in (0..MAX_CPUS).filter(|index| targets & (1 << index) != 0) {
+        apic::send_ipi(unsafe { (*cpu(index)).lapic_id }, TLB_SHOOTDOWN_VECTOR);
+    }
+
+    while SHOOTDOWN_TARGETS.load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+fn tlb_shootdown_interrupt(_regs: &mut Regs) {
+    flush_shot_down_page();
+}
+
+// Drops the page of the shootdown in flight from the calling CPU's TLB, if it is one of the
+// targets. An IPI arriving after it was served this way finds nothing left to do.
+fn flush_shot_down_page() {
+    let me = 1 << percpu::cpu_id();
+    if SHOOTDOWN_TARGETS.load(Ordering::Acquire) & me == 0 {
+        return;
+    }
+
+    let virt = SHOOTDOWN_ADDRESS.load(Ordering::Relaxed);
+    unsafe {
+        asm!("invlpg [{}]", in(reg) virt, options(nostack, preserves_flags));
+    }
+    SHOOTDOWN_TARGETS.fetch_and(!me, Ordering::AcqRel);
+}
@@ -0,0 +1,108 @@
+use core::arch::asm;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// A spinlock that also disables interrupts while it is held, so that an interrupt handler
+/// can never spin on a lock owned by the code it interrupted.
+pub struct Spinlock<T> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Spinlock<T> {}
+unsafe impl<T: Send> Send for Spinlock<T> {}
+
+pub struct SpinlockGuard<'a, T> {
+    lock: &'a Spinlock<T>,
+    interrupts_were_enabled: bool,
+}
+
+fn interrupts_enabled() -> bool {
+    let flags: u64;
+    unsafe {
+        asm!("pushfq", "pop {}", out(reg) flags, options(nomem, preserves_flags));
+    }
+    flags & (1 << 9) != 0
+}
+
+impl<T> Spinlock<T> {
+    pub const fn new(data: T) -> Spinlock<T> {
+        Spinlock {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> SpinlockGuard<'_, T> {
+        let interrupts_were_enabled = interrupts_enabled();
+        unsafe {
+            asm!("cli", options(nomem, nostack));
+        }
+
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+
+        SpinlockGuard {
+            lock: self,
+            interrupts_were_enabled,
+        }
+    }
+
+    /// Like `lock`, but gives up instead of spinning if someone else holds the lock.
+    pub fn try_lock(&self) -> Option<SpinlockGuard<'_, T>> {
+        let interrupts_were_enabled = interrupts_enabled();
+        unsafe {
+            asm!("cli", options(nomem, nostack));
+        }
+
+        if self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            if interrupts_were_enabled {
+                unsafe {
+                    asm!("sti", options(nomem, nostack));
+                }
+            }
+            return None;
+        }
+
+        Some(SpinlockGuard {
+            lock: self,
+            interrupts_were_enabled,
+        })
+    }
+}
+
+impl<T> Deref for SpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+
+        if self.interrupts_were_enabled {
+            unsafe {
+                asm!("sti", options(nomem, nostack));
+            }
+        }
+    }
+}
@@ -1,3 +1,122 @@
-pub fn handler_fn() {
-    
+use core::arch::global_asm;
+
+use crate::cpu;
+use crate::kprint;
+use crate::kprintln;
+use crate::scheduler;
+use crate::uaccess;
+
+global_asm!(include_str!("syscall.S"));
+
+extern "C" {
+    fn syscall_entry();
+}
+
+// sysret loads cs from STAR[63:48] + 16 and ss from STAR[63:48] + 8, syscall loads cs from
+// STAR[47:32] and ss from STAR[47:32] + 8. See gdt::init for the matching layout.
+const STAR: u64 = (0x30 << 48) | (0x28 << 32);
+
+// rflags bits cleared on entry: TF, IF, DF and AC.
+const FMASK: u64 = 0x43700;
+
+/// Registers of the calling task, as pushed by `syscall_entry`. `rax` holds the syscall number
+/// on entry and the value returned to user space on exit.
+#[derive(Debug)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub rax: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub rbx: u64,
+    pub rbp: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rflags: u64, // r11
+    pub rip: u64,    // rcx
+    pub rsp: u64,
+}
+
+pub struct Errno {}
+
+impl Errno {
+    pub const EFAULT: i64 = 14;
+    pub const ENOSYS: i64 = 38;
+}
+
+// the arguments in rdi, rsi, rdx, r10, r8 and r9 order, see userland/hello-world/main.c.
+type SyscallFn = fn(args: [u64; 6]) -> i64;
+
+// indexed by the syscall number.
+static SYSCALL_TABLE: [SyscallFn; 2] = [
+    sys_exit,  // 0
+    sys_write, // 1
+];
+
+#[no_mangle]
+extern "C" fn syscall_handler(frame: *mut SyscallFrame) {
+    let frame = unsafe { &mut *frame };
+    let args = [frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9];
+
+    let result = match SYSCALL_TABLE.get(frame.rax as usize) {
+        Some(syscall) => syscall(args),
+        None => {
+            kprintln!("syscall number {} not recognized!", frame.rax);
+            -Errno::ENOSYS
+        }
+    };
+    frame.rax = result as u64;
+}
+
+/// Where `syscall_entry` goes instead of `sysretq` if the task would return to a non-canonical
+/// address: sysretq would raise #GP in ring 0 with the user's stack and gs base already loaded.
+/// The task would only fault on its next instruction anyway.
+#[no_mangle]
+extern "C" fn syscall_bad_return(frame: *const SyscallFrame) -> ! {
+    let rip = unsafe { (*frame).rip };
+    kprintln!(
+        "task {} killed by SIGSEGV: syscall returns to non-canonical rip {:#x}",
+        scheduler::current_task_id(),
+        rip
+    );
+    scheduler::abort_current();
+}
+
+/// exit(status): ends the calling task, never returns to it.
+fn sys_exit(args: [u64; 6]) -> i64 {
+    scheduler::exit_current(args[0] as i32);
+}
+
+/// write(buf, len): prints `len` bytes at `buf` to the console, returns the number written.
+fn sys_write(args: [u64; 6]) -> i64 {
+    let [buf, len, ..] = args;
+
+    let mut chunk = [0u8; 256];
+    let mut written = 0;
+    while written < len {
+        let n = (len - written).min(chunk.len() as u64) as usize;
+        if let Err(errno) = uaccess::copy_from_user(&mut chunk[..n], buf + written) {
+            return errno;
+        }
+
+        kprint::kprint_bytes(&chunk[..n]);
+        written += n as u64;
+    }
+
+    len as i64
+}
+
+/// Enables the `syscall`/`sysret` instructions and points them at `syscall_entry`.
+pub fn init() {
+    unsafe {
+        cpu::wrmsr(cpu::Msr::IA32_EFER, cpu::rdmsr(cpu::Msr::IA32_EFER) | 1); // SCE
+        cpu::wrmsr(cpu::Msr::IA32_FSTAR, FMASK);
+        cpu::wrmsr(cpu::Msr::IA32_LSTAR, syscall_entry as usize as u64);
+        cpu::wrmsr(cpu::Msr::IA32_STAR, STAR);
+    }
 }
@@ -1,94 +1,398 @@
-use crate::elf::Elf64;
-use crate::pmm::Pmm;
-use crate::kprintln;
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::slice;
+
 use crate::cpu;
-use core::ops::Index;
+use crate::elf::{self, Elf64, ElfError, LoadedElf};
+use crate::gdt::{self, GuardedStack};
+use crate::idt::Regs;
+use crate::kprintln;
+use crate::pmm::{Frame, Pmm, PmmAllocError, FRAME_SIZE, PMM};
+use crate::scheduler;
+use crate::vmm::{self, PageFlags, VmmError};
+
+// the user stack ends right below the last page of the lower half, which stays unmapped.
+const USER_STACK_TOP: u64 = elf::USER_SPACE_TOP - FRAME_SIZE as u64;
+const USER_STACK_SIZE: u64 = 64 * 1024;
+
+// arguments, environment and auxv may take up at most this much of the user stack.
+const MAX_ARGS_SIZE: u64 = USER_STACK_SIZE / 2;
+
+// kernel code and data selectors, see gdt::init.
+const KERNEL_CS: u64 = 0x28;
+const KERNEL_SS: u64 = 0x30;
 
-use bitvec::prelude::*;
+// user code and data selectors with RPL 3.
+const USER_CS: u64 = 0x40 | 3;
+const USER_SS: u64 = 0x38 | 3;
 
-struct RegsWithoutError {}
+// number of u64s in Regs before the iret frame: 15 general purpose registers, the interrupt
+// number and the error code.
+const SAVED_REGS_WORDS: usize = 17;
 
-#[repr(transparent)]
-struct PageTable<'a> {
-    raw: &'a [PageTableEntry<'a>]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TaskState {
+    Queued,
+    Running,
+    #[allow(dead_code)] // nothing pauses a task yet
+    Paused,
+    Finished,
 }
 
-impl<'a> PageTable<'a> {
-    fn new(entries: &'a mut [u64]) -> PageTable<'a> {
-	assert!(entries.len() == 512);
+pub struct Task {
+    pub id: u64, // set by scheduler
+    page_table: Frame,
+    kernel_stack: KernelStack,
+    pub state: TaskState, // set by scheduler
+    pub regs: *mut Regs,  // frame to restore when the task gets the CPU, on its kernel stack
+}
+
+// the raw pointers only ever point into memory owned by the task itself.
+unsafe impl Send for Task {}
+
+/// A task's kernel stack, every interrupt and syscall from ring 3 runs on it. It is a
+/// `GuardedStack` on the heap, whose guard page gets mapped again before the heap has it back.
+struct KernelStack {
+    stack: *mut GuardedStack,
+    guard_frame: Option<u64>, // the frame behind the guard page while it is unmapped
+}
+
+impl KernelStack {
+    fn new() -> Result<KernelStack, TaskError> {
+        // far too big for the stack Box::new would build it on first.
+        let stack = unsafe { alloc_zeroed(Layout::new::<GuardedStack>()) } as *mut GuardedStack;
+        if stack.is_null() {
+            return Err(TaskError::OutOfMemory);
+        }
+
+        let guard_frame = gdt::guard_stack("task kernel", stack);
+        Ok(KernelStack { stack, guard_frame })
+    }
+
+    fn top(&self) -> u64 {
+        gdt::stack_top(self.stack)
+    }
+
+    fn words(&mut self) -> &mut [u64] {
+        let bottom = self.top() - gdt::STACK_SIZE as u64;
+        unsafe { slice::from_raw_parts_mut(bottom as *mut u64, gdt::STACK_SIZE / 8) }
+    }
+}
 
-        PageTable {
-            raw: unsafe { core::mem::transmute(entries) }
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        if let Some(phys) = self.guard_frame {
+            let flags = PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::NO_EXECUTE;
+            let guard_page = gdt::guard_page(self.stack);
+            vmm::map(
+                &vmm::kernel_address_space(),
+                guard_page,
+                phys,
+                flags,
+                &mut PMM.lock(),
+            )
+            .expect("could not map the guard page of a task's kernel stack again");
         }
+
+        unsafe { dealloc(self.stack as *mut u8, Layout::new::<GuardedStack>()) };
     }
+}
 
+#[allow(dead_code)] // the payload is only read through Debug
+#[derive(Debug)]
+pub enum TaskError {
+    Elf(ElfError),
+    OutOfMemory,
+    ArgsTooLong,
+    AddressInUse, // something is already mapped where the task needs its own pages
+    NotMapped,
+    HugePage, // a 2MiB/1GiB page sits where the task needs page tables
 }
 
+impl From<ElfError> for TaskError {
+    fn from(e: ElfError) -> Self {
+        TaskError::Elf(e)
+    }
+}
 
-#[repr(transparent)]
-struct PageTableEntry<'a> {
-    raw: &'a mut u64
+impl From<PmmAllocError> for TaskError {
+    fn from(_: PmmAllocError) -> Self {
+        TaskError::OutOfMemory
+    }
 }
 
-impl<'a> PageTableEntry<'a> {
-    fn new(entry: &'a mut u64) -> PageTableEntry<'a> {
-        PageTableEntry {
-            raw: entry
+impl From<VmmError> for TaskError {
+    fn from(e: VmmError) -> Self {
+        match e {
+            VmmError::OutOfMemory => TaskError::OutOfMemory,
+            VmmError::AlreadyMapped => TaskError::AddressInUse,
+            VmmError::NotMapped => TaskError::NotMapped,
+            VmmError::HugePage => TaskError::HugePage,
         }
     }
+}
+
+/// The argument vector and environment a task starts with.
+pub struct Args<'a> {
+    pub argv: &'a [&'a str],
+    pub envp: &'a [&'a str],
+}
+
+pub struct AuxType {}
 
-    fn is_present(&self) -> bool {
-	*self.raw.view_bits::<Lsb0>().get(0).unwrap()
+impl AuxType {
+    pub const AT_NULL: u64 = 0;
+    pub const AT_PHDR: u64 = 3;
+    pub const AT_PHENT: u64 = 4;
+    pub const AT_PHNUM: u64 = 5;
+    pub const AT_PAGESZ: u64 = 6;
+    pub const AT_ENTRY: u64 = 9;
+    pub const AT_RANDOM: u64 = 25;
+}
+
+// Seed for AT_RANDOM. Only good enough to differ between tasks, there is no entropy source yet.
+fn random_bytes() -> [u8; 16] {
+    let mut state = cpu::rdtsc();
+    let mut next = || {
+        // splitmix64
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    };
+
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&next().to_le_bytes());
+    bytes[8..].copy_from_slice(&next().to_le_bytes());
+    bytes
+}
+
+/// Lays out the top of the initial user stack as the System V ABI wants it. From the returned
+/// stack pointer up: argc, the argv pointers, NULL, the envp pointers, NULL, the auxv pairs
+/// ending with AT_NULL, and finally the AT_RANDOM bytes and the strings they all point to.
+fn user_stack_image(args: &Args, program: &LoadedElf) -> Result<(Vec<u8>, u64), TaskError> {
+    let strings_size: usize = args
+        .argv
+        .iter()
+        .chain(args.envp)
+        .map(|arg| arg.len() + 1)
+        .sum();
+    if strings_size as u64 > MAX_ARGS_SIZE {
+        return Err(TaskError::ArgsTooLong);
     }
+
+    let random_ptr = USER_STACK_TOP - 16 - strings_size as u64;
+    let auxv = [
+        (AuxType::AT_PHDR, program.phdr),
+        (AuxType::AT_PHENT, program.phent),
+        (AuxType::AT_PHNUM, program.phnum),
+        (AuxType::AT_PAGESZ, FRAME_SIZE as u64),
+        (AuxType::AT_ENTRY, program.entry),
+        (AuxType::AT_RANDOM, random_ptr),
+        (AuxType::AT_NULL, 0),
+    ];
+
+    let n_words = 1 + args.argv.len() + 1 + args.envp.len() + 1 + 2 * auxv.len();
+    let stack_ptr = (random_ptr - 8 * n_words as u64) & !0xf;
+    if USER_STACK_TOP - stack_ptr > MAX_ARGS_SIZE {
+        return Err(TaskError::ArgsTooLong);
+    }
+
+    let mut words: Vec<u64> = Vec::with_capacity(n_words);
+    let mut string_ptr = random_ptr + 16;
+
+    words.push(args.argv.len() as u64);
+    for strings in [args.argv, args.envp] {
+        for string in strings {
+            words.push(string_ptr);
+            string_ptr += string.len() as u64 + 1;
+        }
+        words.push(0);
+    }
+    for (aux_type, value) in auxv {
+        words.push(aux_type);
+        words.push(value);
+    }
+
+    let mut image = vec![0u8; (USER_STACK_TOP - stack_ptr) as usize];
+    for (bytes, word) in image.chunks_exact_mut(8).zip(&words) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+
+    let mut offset = (random_ptr - stack_ptr) as usize;
+    image[offset..offset + 16].copy_from_slice(&random_bytes());
+    offset += 16;
+    for string in args.argv.iter().chain(args.envp) {
+        image[offset..offset + string.len()].copy_from_slice(string.as_bytes());
+        offset += string.len() + 1; // the NUL is already there
+    }
+
+    Ok((image, stack_ptr))
 }
 
+/// Maps a zeroed user stack right below USER_STACK_TOP with `image` at its very top.
+fn map_user_stack(page_table: &Frame, image: &[u8], pmm: &mut Pmm) -> Result<(), TaskError> {
+    let flags = PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::USER | PageFlags::NO_EXECUTE;
+    let image_start = USER_STACK_TOP - image.len() as u64;
+
+    for page in (USER_STACK_TOP - USER_STACK_SIZE..USER_STACK_TOP).step_by(FRAME_SIZE) {
+        let frame = pmm.alloc_frame(1)?;
+        let phy_ptr = frame.phy_ptr();
 
-impl<'a> Index<usize> for PageTable<'a> {
-    type Output = PageTableEntry<'a>;
-    
-    fn index(&self, idx: usize) -> &Self::Output {
-	&self.raw[idx]
+        let bytes = frame.to_higher_half_slice_mut::<u8>();
+        bytes.fill(0);
+
+        let start = page.max(image_start);
+        let end = page + FRAME_SIZE as u64;
+        if start < end {
+            bytes[(start - page) as usize..].copy_from_slice(
+                &image[(start - image_start) as usize..(end - image_start) as usize],
+            );
+        }
+
+        if let Err(e) = vmm::map(page_table, page, phy_ptr, flags, pmm) {
+            pmm.dealloc_frame(Frame::from_u64(phy_ptr, FRAME_SIZE));
+            return Err(e.into());
+        }
     }
-    
+
+    Ok(())
 }
 
-enum TaskState {
-    Queued,
-    Running,
-    Finished,
-    Paused,
+/// Builds the frame the timer interrupt wrapper restores when it switches to a task for the
+/// first time, so that its `iretq` lands in `task_entry` on top of the task's own kernel stack,
+/// with `entry` and `user_stack` as its arguments.
+fn initial_frame(kernel_stack: &mut [u64], entry: u64, user_stack: u64) -> *mut Regs {
+    let top = kernel_stack.as_ptr_range().end as u64;
+    let len = kernel_stack.len();
+
+    let frame = &mut kernel_stack[len - (SAVED_REGS_WORDS + 5)..];
+    frame.fill(0);
+
+    frame[5] = user_stack; // rsi
+    frame[6] = entry; // rdi
+    frame[15] = 32; // interrupt number
+
+    // what the CPU pushed when the timer fired: rip, cs, rflags, rsp, ss
+    frame[17] = task_entry as usize as u64;
+    frame[18] = KERNEL_CS;
+    frame[19] = 0x202; // IF set
+    frame[20] = (top & !0xf) - 8; // as if task_entry had been called
+    frame[21] = KERNEL_SS;
+
+    frame.as_mut_ptr() as *mut Regs
+}
+
+/// First code every task runs, in ring 0 on its own kernel stack.
+extern "C" fn task_entry(entry: u64, user_stack: u64) -> ! {
+    kprintln!("task {} is running", scheduler::current_task_id());
+    enter_user(entry, user_stack);
+}
+
+/// Drops to ring 3 at `entry` with `user_stack` as stack pointer and interrupts enabled. From
+/// then on interrupts and syscalls come back in on the kernel stack set up by the scheduler.
+pub fn enter_user(entry: u64, user_stack: u64) -> ! {
+    unsafe {
+        asm!(
+            "push {ss}",
+            "push {rsp}",
+            "push {rflags}",
+            "push {cs}",
+            "push {rip}",
+            // nothing of the kernel's state may leak into the task.
+            "xor eax, eax",
+            "xor ebx, ebx",
+            "xor ecx, ecx",
+            "xor edx, edx",
+            "xor esi, esi",
+            "xor edi, edi",
+            "xor ebp, ebp",
+            "xor r8d, r8d",
+            "xor r9d, r9d",
+            "xor r10d, r10d",
+            "xor r11d, r11d",
+            "xor r12d, r12d",
+            "xor r13d, r13d",
+            "xor r14d, r14d",
+            "xor r15d, r15d",
+            // no interrupt may see the user's gs base while still in ring 0.
+            "cli",
+            "swapgs",
+            "iretq",
+            ss = in(reg) USER_SS,
+            rsp = in(reg) user_stack,
+            rflags = in(reg) 0x202_u64, // IF set
+            cs = in(reg) USER_CS,
+            rip = in(reg) entry,
+            options(noreturn)
+        );
+    }
 }
 
-pub struct Task<'a> {
-    id: u64, // set by scheduler
-    page_table_address: PageTable<'a>,
-    stack_address: &'a [u64],
-    entry_address: *const u8,
-    state: TaskState, // set by scheduler
-    regs: RegsWithoutError,
-}
-
-struct Args {}
-
-impl<'a> Task<'a> {
-    pub fn new(
-        pmm: &mut Pmm,
-        //_current_page_table_address: &'a PageTable<'a>,
-        //_program_elf: Elf64,
-        //_args: &[Args],
-    ) {
-        // Task<'a> {
-
-	unsafe {
-	    let current_page_table: &mut [u64] = cpu::cr3().to_higher_half_slice_mut();
-	    let page_table = PageTable::new(current_page_table);
-	    for i in 0..512 {
-		kprintln!("{} {}", i, page_table[i].is_present());
-	    }
-	}
-
-        // let page_table_address: &[u64] =
-        //     pmm.alloc_frame(1).unwrap().to_higher_half_slice();
-	// let page_table = PageTable::new(page_table_address);
+impl Task {
+    /// Loads `program_elf` into a new address space of its own, with a user stack holding
+    /// `args`. The lower half of that address space is private to the task, the upper half is
+    /// shared with the kernel.
+    pub fn new(program_elf: &Elf64, args: &Args) -> Result<Task, TaskError> {
+        let mut kernel_stack = KernelStack::new()?;
+
+        let user_stack = USER_STACK_TOP - USER_STACK_SIZE..USER_STACK_TOP;
+        let program = elf::load(
+            program_elf,
+            elf::PIE_LOAD_BIAS,
+            &user_stack,
+            &mut PMM.lock(),
+        )?;
+
+        // built before taking the PMM lock, the heap may need it to grow.
+        let (image, user_stack_ptr) = match user_stack_image(args, &program) {
+            Ok(stack) => stack,
+            Err(e) => {
+                vmm::destroy_address_space(program.page_table, &mut PMM.lock());
+                return Err(e);
+            }
+        };
+
+        let mut pmm = PMM.lock();
+        if let Err(e) = map_user_stack(&program.page_table, &image, &mut pmm) {
+            vmm::destroy_address_space(program.page_table, &mut pmm);
+            return Err(e);
+        }
+        drop(pmm);
+
+        let regs = initial_frame(kernel_stack.words(), program.entry, user_stack_ptr);
+
+        Ok(Task {
+            id: 0,
+            page_table: program.page_table,
+            kernel_stack,
+            state: TaskState::Queued,
+            regs,
+        })
+    }
+
+    /// Address right above the task's kernel stack, where interrupts and syscalls from ring 3
+    /// start.
+    pub fn kernel_stack_top(&self) -> u64 {
+        self.kernel_stack.top()
+    }
+
+    /// Whether a fault at `addr` hit the guard page below the task's kernel stack.
+    pub fn overflowed_kernel_stack(&self, addr: u64) -> bool {
+        gdt::hits_guard_page(self.kernel_stack.stack, addr)
+    }
+
+    /// Switches the CPU over to the task's address space.
+    pub fn activate_address_space(&self) {
+        vmm::switch_to(&self.page_table);
+    }
+
+    /// Frees the task's address space (its page tables and every user frame mapped in it) and
+    /// its kernel stack. Must not be called while running on that kernel stack.
+    pub fn destroy(self) {
+        vmm::destroy_address_space(self.page_table, &mut PMM.lock());
     }
 }
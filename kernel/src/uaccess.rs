@@ -0,0 +1,69 @@
+#![allow(dead_code)]
+use core::arch::global_asm;
+
+use crate::cpu;
+use crate::elf::USER_SPACE_TOP;
+use crate::pmm::FRAME_SIZE;
+use crate::syscall::Errno;
+use crate::vmm::{self, PageFlags};
+
+global_asm!(include_str!("uaccess.S"));
+
+extern "C" {
+    fn user_copy(dst: *mut u8, src: *const u8, len: u64) -> u64;
+    fn user_copy_fault();
+    fn user_copy_fixup();
+}
+
+/// Checks that `[addr, addr + len)` lies in the user half and that every page of it is mapped
+/// with at least `flags` in the current address space.
+fn check_range(addr: u64, len: usize, flags: u64) -> bool {
+    let end = match addr.checked_add(len as u64) {
+        Some(end) if end <= USER_SPACE_TOP => end,
+        _ => return false,
+    };
+
+    let pml4 = unsafe { cpu::cr3() };
+    let mut page = addr & !(FRAME_SIZE as u64 - 1);
+    while page < end {
+        match vmm::effective_flags(&pml4, page) {
+            Some(page_flags) if page_flags & flags == flags => {}
+            _ => return false,
+        }
+        page += FRAME_SIZE as u64;
+    }
+
+    true
+}
+
+fn copy(dst: *mut u8, src: *const u8, len: usize) -> Result<(), i64> {
+    match unsafe { user_copy(dst, src, len as u64) } {
+        0 => Ok(()),
+        _ => Err(-Errno::EFAULT),
+    }
+}
+
+/// Fills `dst` with the bytes at the user address `src`, or fails with -EFAULT.
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), i64> {
+    if !check_range(src, dst.len(), PageFlags::PRESENT | PageFlags::USER) {
+        return Err(-Errno::EFAULT);
+    }
+
+    copy(dst.as_mut_ptr(), src as *const u8, dst.len())
+}
+
+/// Writes `src` to the user address `dst`, or fails with -EFAULT.
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), i64> {
+    let flags = PageFlags::PRESENT | PageFlags::USER | PageFlags::WRITABLE;
+    if !check_range(dst, src.len(), flags) {
+        return Err(-Errno::EFAULT);
+    }
+
+    copy(dst as *mut u8, src.as_ptr(), src.len())
+}
+
+/// Called for page faults in the kernel: if `rip` is the copy from or to user memory, returns
+/// where to resume so that the copy reports -EFAULT instead.
+pub fn fixup(rip: u64) -> Option<u64> {
+    (rip == user_copy_fault as usize as u64).then_some(user_copy_fixup as usize as u64)
+}
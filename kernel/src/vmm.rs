@@ -1,4 +1,383 @@
-struct PageTableEntry {
-j
-    
+#![allow(dead_code)]
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::boot;
+use crate::cpu;
+use crate::pmm::{Frame, Pmm, PmmAllocError, FRAME_SIZE, PMM};
+use crate::smp;
+
+const ENTRIES_PER_TABLE: usize = 512;
+
+// bits 12..51 of an entry hold the physical address of the next table/page.
+const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+// first address of the higher half, which is shared by every address space.
+const KERNEL_SPACE_START: u64 = 0xffff_8000_0000_0000;
+
+// the kernel's own address space, a copy of the upper half Limine handed over to us.
+static KERNEL_PML4: AtomicU64 = AtomicU64::new(0);
+
+pub struct PageFlags {}
+
+impl PageFlags {
+    pub const PRESENT: u64 = 1 << 0;
+    pub const WRITABLE: u64 = 1 << 1;
+    pub const USER: u64 = 1 << 2;
+    pub const WRITE_THROUGH: u64 = 1 << 3;
+    pub const NO_CACHE: u64 = 1 << 4;
+    pub const ACCESSED: u64 = 1 << 5;
+    pub const DIRTY: u64 = 1 << 6;
+    pub const HUGE: u64 = 1 << 7; // 1GiB page in a PDPT, 2MiB page in a PD.
+    pub const GLOBAL: u64 = 1 << 8;
+    pub const NO_EXECUTE: u64 = 1 << 63; // Limine leaves EFER.NXE enabled for us.
+}
+
+#[derive(Debug)]
+pub enum VmmError {
+    OutOfMemory,
+    AlreadyMapped,
+    NotMapped,
+    HugePage, // the walk ran into a 2MiB/1GiB page where a table was expected.
+}
+
+impl From<PmmAllocError> for VmmError {
+    fn from(_: PmmAllocError) -> Self {
+        VmmError::OutOfMemory
+    }
+}
+
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct PageTableEntry(u64);
+
+impl PageTableEntry {
+    pub fn is_present(&self) -> bool {
+        self.0 & PageFlags::PRESENT != 0
+    }
+
+    pub fn is_huge(&self) -> bool {
+        self.0 & PageFlags::HUGE != 0
+    }
+
+    pub fn address(&self) -> u64 {
+        self.0 & ADDRESS_MASK
+    }
+
+    pub fn flags(&self) -> u64 {
+        self.0 & !ADDRESS_MASK
+    }
+}
+
+/// Returns the 512 entries of the page table stored in `frame`.
+pub fn page_table<'a>(frame: &Frame) -> &'a mut [PageTableEntry] {
+    table_at(frame.phy_ptr())
+}
+
+fn table_at<'a>(phy_ptr: u64) -> &'a mut [PageTableEntry] {
+    Frame::from_u64(phy_ptr, FRAME_SIZE).to_higher_half_slice_mut()
+}
+
+fn table_indices(virt: u64) -> [usize; 4] {
+    [
+        ((virt >> 39) & 0x1ff) as usize, // PML4
+        ((virt >> 30) & 0x1ff) as usize, // PDPT
+        ((virt >> 21) & 0x1ff) as usize, // PD
+        ((virt >> 12) & 0x1ff) as usize, // PT
+    ]
+}
+
+/// Returns the table the entry points to, allocating (and zeroing) a fresh one if it is not
+/// present. Intermediate tables are maximally permissive, the leaf decides the real rights.
+fn next_table_or_alloc<'a>(
+    table: &mut [PageTableEntry],
+    index: usize,
+    pmm: &mut Pmm,
+) -> Result<&'a mut [PageTableEntry], VmmError> {
+    if !table[index].is_present() {
+        let frame = pmm.alloc_frame(1)?;
+        let phy_ptr = frame.phy_ptr();
+        frame.to_higher_half_slice_mut::<u64>().fill(0);
+
+        table[index] =
+            PageTableEntry(phy_ptr | PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::USER);
+    }
+
+    if table[index].is_huge() {
+        return Err(VmmError::HugePage);
+    }
+    Ok(table_at(table[index].address()))
+}
+
+/// Walks down to the PT level entry of `virt` without allocating anything.
+fn leaf_entry<'a>(pml4: &Frame, virt: u64) -> Result<&'a mut PageTableEntry, VmmError> {
+    let [l4, l3, l2, l1] = table_indices(virt);
+    let mut table = table_at(pml4.phy_ptr());
+
+    for index in [l4, l3, l2] {
+        let entry = table[index];
+        if !entry.is_present() {
+            return Err(VmmError::NotMapped);
+        }
+        if entry.is_huge() {
+            return Err(VmmError::HugePage);
+        }
+        table = table_at(entry.address());
+    }
+
+    if !table[l1].is_present() {
+        return Err(VmmError::NotMapped);
+    }
+    Ok(&mut table[l1])
+}
+
+fn is_active(pml4: &Frame) -> bool {
+    unsafe { cpu::cr3() }.phy_ptr() == pml4.phy_ptr()
+}
+
+/// Drops a stale translation from the TLB. Only needed when the page is visible from the
+/// currently loaded CR3: every other address space gets a clean TLB when it is switched to.
+/// Kernel pages are visible on every CPU, so the others drop theirs too.
+fn invalidate(pml4: &Frame, virt: u64) {
+    if virt >= KERNEL_SPACE_START || is_active(pml4) {
+        unsafe {
+            asm!("invlpg [{}]", in(reg) virt, options(nostack, preserves_flags));
+        }
+    }
+    if virt >= KERNEL_SPACE_START {
+        smp::tlb_shootdown(virt);
+    }
+}
+
+// Copies the table at `phy_ptr` (level 4 is the PML4, level 1 a PT) and every table below it
+// into fresh frames. The pages they map are shared with the original.
+fn copy_table(phy_ptr: u64, level: usize, pmm: &mut Pmm) -> Result<u64, VmmError> {
+    let frame = pmm.alloc_frame(1)?;
+    let copy = page_table(&frame);
+    copy.copy_from_slice(table_at(phy_ptr));
+
+    if level > 1 {
+        for entry in copy.iter_mut() {
+            if entry.is_present() && !entry.is_huge() {
+                let table = copy_table(entry.address(), level - 1, pmm)?;
+                *entry = PageTableEntry(table | entry.flags());
+            }
+        }
+    }
+    Ok(frame.phy_ptr())
+}
+
+/// Switches to a copy of the upper half of Limine's page tables, which live in bootloader
+/// reclaimable memory, and makes it the kernel's address space. Must run before any other
+/// address space is created, as those share the kernel's tables.
+///
+/// Every upper half PML4 slot gets its PDPT here, empty ones included. Address spaces only copy
+/// the PML4 entries, so a slot filled in later would never reach those that already exist.
+pub fn init() {
+    let mut pmm = PMM.lock();
+    let limine_pml4 = table_at(unsafe { cpu::cr3() }.phy_ptr());
+
+    let pml4 = pmm
+        .alloc_frame(1)
+        .expect("vmm: out of memory for the kernel's PML4");
+    let table = page_table(&pml4);
+    let half = ENTRIES_PER_TABLE / 2;
+    table[..half].fill(PageTableEntry(0));
+
+    for index in half..ENTRIES_PER_TABLE {
+        let entry = limine_pml4[index];
+        table[index] = if entry.is_present() {
+            let copy = copy_table(entry.address(), 3, &mut pmm)
+                .expect("vmm: out of memory for the kernel's page tables");
+            PageTableEntry(copy | entry.flags())
+        } else {
+            let frame = pmm
+                .alloc_frame(1)
+                .expect("vmm: out of memory for the kernel's page tables");
+            let phy_ptr = frame.phy_ptr();
+            frame.to_higher_half_slice_mut::<u64>().fill(0);
+            PageTableEntry(phy_ptr | PageFlags::PRESENT | PageFlags::WRITABLE)
+        };
+    }
+
+    KERNEL_PML4.store(pml4.phy_ptr(), Ordering::Relaxed);
+    switch_to(&pml4);
+}
+
+/// The address space that only contains the kernel, used whenever no task is running.
+pub fn kernel_address_space() -> Frame {
+    Frame::from_u64(KERNEL_PML4.load(Ordering::Relaxed), FRAME_SIZE)
+}
+
+/// Allocates a new PML4 whose upper half (kernel image + HHDM) is shared with the kernel's
+/// address space and whose lower half is empty.
+pub fn new_address_space(pmm: &mut Pmm) -> Result<Frame, VmmError> {
+    let frame = pmm.alloc_frame(1)?;
+    let pml4 = page_table(&frame);
+    let kernel = page_table(&kernel_address_space());
+
+    let half = ENTRIES_PER_TABLE / 2;
+    pml4[..half].fill(PageTableEntry(0));
+    pml4[half..].copy_from_slice(&kernel[half..]);
+
+    Ok(frame)
+}
+
+// Walks the table at `phy_ptr` (level 4 is the PML4, level 1 a PT), freeing every user frame
+// mapped below it, the tables themselves, and finally the table at `phy_ptr`.
+fn free_table(phy_ptr: u64, level: usize, pmm: &mut Pmm) {
+    let table = table_at(phy_ptr);
+
+    // the upper half of a PML4 belongs to the kernel and is shared by everyone.
+    let entries = if level == 4 {
+        &table[..ENTRIES_PER_TABLE / 2]
+    } else {
+        &table[..]
+    };
+
+    for entry in entries {
+        if !entry.is_present() {
+            continue;
+        }
+
+        if level == 1 {
+            if entry.flags() & PageFlags::USER != 0 {
+                pmm.dealloc_frame(Frame::from_u64(entry.address(), FRAME_SIZE));
+            }
+        } else if !entry.is_huge() {
+            free_table(entry.address(), level - 1, pmm);
+        }
+    }
+
+    pmm.dealloc_frame(Frame::from_u64(phy_ptr, FRAME_SIZE));
+}
+
+/// Gives every user page, every lower half page table and the PML4 of an address space back to
+/// the PMM. The address space must not be the active one.
+pub fn destroy_address_space(pml4: Frame, pmm: &mut Pmm) {
+    assert!(
+        !is_active(&pml4),
+        "cannot destroy the active address space!"
+    );
+
+    free_table(pml4.phy_ptr(), 4, pmm);
+}
+
+/// Loads `pml4` into CR3 unless it is already active, which would needlessly flush the TLB.
+pub fn switch_to(pml4: &Frame) {
+    if !is_active(pml4) {
+        unsafe { cpu::set_cr3(pml4) };
+    }
+}
+
+/// Maps the 4KiB page at `virt` to the frame at `phys` inside the address space rooted at
+/// `pml4`. Missing intermediate tables are allocated from `pmm`.
+pub fn map(pml4: &Frame, virt: u64, phys: u64, flags: u64, pmm: &mut Pmm) -> Result<(), VmmError> {
+    assert!(virt % FRAME_SIZE as u64 == 0, "virt must be page aligned!");
+    assert!(phys % FRAME_SIZE as u64 == 0, "phys must be page aligned!");
+
+    let [l4, l3, l2, l1] = table_indices(virt);
+
+    let pml4_table = table_at(pml4.phy_ptr());
+    let pdpt = next_table_or_alloc(pml4_table, l4, pmm)?;
+    let pd = next_table_or_alloc(pdpt, l3, pmm)?;
+    let pt = next_table_or_alloc(pd, l2, pmm)?;
+
+    if pt[l1].is_present() {
+        return Err(VmmError::AlreadyMapped);
+    }
+
+    // a non-present entry is never cached, so there is nothing to invalidate.
+    pt[l1] = PageTableEntry((phys & ADDRESS_MASK) | flags | PageFlags::PRESENT);
+    Ok(())
+}
+
+/// Removes the mapping of the page at `virt` and returns the physical address it was mapped to,
+/// the caller decides whether the frame should go back to the PMM.
+pub fn unmap(pml4: &Frame, virt: u64) -> Result<u64, VmmError> {
+    let entry = leaf_entry(pml4, virt)?;
+    let phys = entry.address();
+
+    *entry = PageTableEntry(0);
+    invalidate(pml4, virt);
+
+    Ok(phys)
+}
+
+/// Replaces the flags of the already mapped page at `virt`.
+pub fn protect(pml4: &Frame, virt: u64, flags: u64) -> Result<(), VmmError> {
+    let entry = leaf_entry(pml4, virt)?;
+
+    *entry = PageTableEntry(entry.address() | (flags & !ADDRESS_MASK) | PageFlags::PRESENT);
+    invalidate(pml4, virt);
+
+    Ok(())
+}
+
+/// Returns the physical address `virt` maps to inside the address space rooted at `pml4`,
+/// following 1GiB and 2MiB pages as well (the HHDM is mapped with those).
+pub fn translate(pml4: &Frame, virt: u64) -> Option<u64> {
+    let mut table = table_at(pml4.phy_ptr());
+
+    for (level, index) in table_indices(virt).into_iter().enumerate() {
+        let entry = table[index];
+        if !entry.is_present() {
+            return None;
+        }
+
+        // each level up covers 512 times as much memory: 4KiB, 2MiB, 1GiB.
+        let page_size = (FRAME_SIZE as u64) << (9 * (3 - level));
+        if level == 3 || (level > 0 && entry.is_huge()) {
+            return Some((entry.address() & !(page_size - 1)) + (virt & (page_size - 1)));
+        }
+        table = table_at(entry.address());
+    }
+
+    None
+}
+
+/// Returns the flags that really apply to `virt`: WRITABLE and USER only if every level of the
+/// walk grants them, NO_EXECUTE if any level sets it. None if `virt` is not mapped.
+pub fn effective_flags(pml4: &Frame, virt: u64) -> Option<u64> {
+    let mut table = table_at(pml4.phy_ptr());
+    let mut rights = PageFlags::WRITABLE | PageFlags::USER;
+    let mut no_execute = 0;
+
+    for (level, index) in table_indices(virt).into_iter().enumerate() {
+        let entry = table[index];
+        if !entry.is_present() {
+            return None;
+        }
+
+        rights &= entry.flags();
+        no_execute |= entry.flags() & PageFlags::NO_EXECUTE;
+
+        if level == 3 || (level > 0 && entry.is_huge()) {
+            let leaf = entry.flags() & !(PageFlags::WRITABLE | PageFlags::USER);
+            return Some(leaf | rights | no_execute);
+        }
+        table = table_at(entry.address());
+    }
+
+    None
+}
+
+/// Makes the device registers in the page at `phys` reachable, uncached, at their HHDM address
+/// and returns it. Limine's HHDM is not guaranteed to cover anything but RAM.
+pub fn map_mmio(phys: u64, pmm: &mut Pmm) -> Result<u64, VmmError> {
+    let hhdm = boot::hhdm_offset();
+    let phys = phys & !(FRAME_SIZE as u64 - 1);
+    let virt = phys + hhdm;
+
+    let flags = PageFlags::PRESENT
+        | PageFlags::WRITABLE
+        | PageFlags::WRITE_THROUGH
+        | PageFlags::NO_CACHE
+        | PageFlags::NO_EXECUTE;
+
+    match map(&kernel_address_space(), virt, phys, flags, pmm) {
+        // already covered by the HHDM, the MTRRs keep MMIO ranges uncached anyway.
+        Ok(()) | Err(VmmError::AlreadyMapped) | Err(VmmError::HugePage) => Ok(virt),
+        Err(e) => Err(e),
+    }
 }
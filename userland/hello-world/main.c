@@ -15,11 +15,11 @@
  * https://gcc.gnu.org/onlinedocs/gcc/Machine-Constraints.html
  */
 
-extern void sysexit() {
+extern void sysexit(int status) {
 		__asm__ volatile(
 				"syscall"
-				: : "a"(0)
-				:
+				: : "a"(0), "D"(status)
+				: "rcx", "r11", "memory"
 				);
 }
 
@@ -32,10 +32,12 @@ void printf(char * f_str, ...) {
     
     int size = kvsprintf(buffer, f_str, args);
 
+		long ret = 1; // the syscall number goes in, the result comes back out
 		__asm__ volatile(
 				"syscall"
-				: : "a"(1), "D"(buffer), "S"(size)
-				:
+				: "+a"(ret)
+				: "D"(buffer), "S"(size)
+				: "rcx", "r11", "memory"
 				);
     va_end(args);
 }
@@ -49,8 +51,7 @@ int pow(int base, int exp) {
 }
 
 int exit(int status) {
-	(void) status;
-	sysexit();
+	sysexit(status);
 }
 #else
 #include <stdio.h>